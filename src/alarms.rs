@@ -0,0 +1,76 @@
+use rusoto_cloudwatch::{CloudWatch, CloudWatchClient, DescribeAlarmsInput};
+use deployment::DeploymentContext;
+use utils::{aws_call, dry_run, paginate};
+
+/// The alarms that gate deployments of `app` to `env`, from the
+/// application's `alarms` block in the universe.  An entry ending in `*` is a
+/// name prefix; anything else is an exact alarm name.
+pub fn alarm_selectors(u: &yaml_rust::Yaml, app: &str, env: &str) -> Vec<String> {
+    u["applications"][app]["alarms"][env].as_vec().cloned().unwrap_or_default().iter()
+        .filter_map(|a| a.as_str().map(|s| s.to_string()))
+        .collect()
+}
+
+/// Names of the alarms matching `selectors` that are currently in ALARM.
+pub fn alarms_in_alarm(r: rusoto_core::Region, selectors: &[String]) -> Vec<String> {
+    let cw_client = CloudWatchClient::new(r.to_owned());
+    let (prefixes, names): (Vec<&String>, Vec<&String>) = selectors.iter().partition(|s| s.ends_with('*'));
+
+    let mut requests: Vec<DescribeAlarmsInput> = prefixes.iter()
+        .map(|p| DescribeAlarmsInput {
+            alarm_name_prefix: Some(p.trim_end_matches('*').to_string()),
+            state_value: Some("ALARM".to_string()),
+            ..Default::default()
+        })
+        .collect();
+    // DescribeAlarms takes at most 100 names per request.
+    for chunk in names.chunks(100) {
+        requests.push(DescribeAlarmsInput {
+            alarm_names: Some(chunk.iter().map(|n| n.to_string()).collect()),
+            state_value: Some("ALARM".to_string()),
+            ..Default::default()
+        });
+    };
+
+    let mut firing = Vec::new();
+    for req in requests {
+        let alarms = paginate(|token| {
+            let req = DescribeAlarmsInput { next_token: token, ..req.clone() };
+            match cw_client.describe_alarms(req).sync() {
+                Ok(res) => (res.metric_alarms.unwrap_or_default(), res.next_token),
+                Err(error) => panic!("ERROR: {:?}", error),
+            }
+        });
+        for n in alarms.into_iter().filter_map(|a| a.alarm_name) {
+            if !firing.contains(&n) {
+                firing.push(n);
+            };
+        };
+    };
+
+    firing
+}
+
+/// Fail if any alarm gating this deployment is in ALARM.  `when` says which
+/// part of the deployment is being checked, for the error message.
+pub fn check_alarms(c: &DeploymentContext, when: &str) -> Result<(), String> {
+    if c.alarms.is_empty() || dry_run() {
+        return Ok(())
+    };
+
+    let firing = alarms_in_alarm(c.region.clone(), &c.alarms);
+    if !firing.is_empty() {
+        return Err(format!("alarm(s) in ALARM {}: {}", when, firing.join(", ")))
+    };
+
+    debug!("no gating alarms in ALARM {}", when);
+    Ok(())
+}
+
+/// Plan line for an alarm check.
+pub fn check_alarms_calls(c: &DeploymentContext, when: &str) -> Vec<String> {
+    if c.alarms.is_empty() {
+        return Vec::new()
+    };
+    vec![aws_call("cloudwatch", "DescribeAlarms", format!("({}) fail if any is in ALARM {}", c.alarms.join(", "), when))]
+}
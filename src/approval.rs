@@ -0,0 +1,174 @@
+use std::io::{BufRead, BufReader, IsTerminal, Write};
+use std::net::TcpListener;
+use std::sync::{Mutex, OnceLock, mpsc};
+use std::{thread, time};
+use futures::{Future, Stream};
+use http::Uri;
+use rusoto_s3::{S3, S3Client, GetObjectRequest};
+use deployment::DeploymentContext;
+use notify::notify;
+use utils::dry_run;
+
+/// Where an approval gate waits for a decision, from the `approval` setting:
+/// `tty` asks on the terminal, `s3://bucket/key` and any other value (a local
+/// path) wait for that object or file to appear, and `http://host:port`
+/// listens for `POST /approve` or `POST /reject`.  A file or object whose
+/// contents are `reject` rejects the deployment; anything else approves it.
+pub fn approval_calls(c: &DeploymentContext) -> Vec<String> {
+    match c.deploy.approval {
+        Some(ref gate) => vec![format!("wait up to {} minutes for approval via {}; roll back if it does not come", c.deploy.approval_timeout, gate)],
+        None => Vec::new(),
+    }
+}
+
+/// Wait for someone to approve rolling the deployment out past the canary.
+/// Returns an `Err`, which rolls the deployment back, if it is rejected or
+/// nobody decides within the approval timeout.
+pub fn await_approval(c: &DeploymentContext) -> Result<(), String> {
+    let gate = match c.deploy.approval {
+        Some(ref g) => g.clone(),
+        None => return Ok(()),
+    };
+    if dry_run() {
+        return Ok(())
+    };
+
+    let timeout = time::Duration::from_secs(c.deploy.approval_timeout * 60);
+    info!("waiting up to {} minutes for approval via {}", c.deploy.approval_timeout, gate);
+    notify(c, "awaiting_approval", &format!("canary is ready; approve via {} within {} minutes", gate, c.deploy.approval_timeout));
+
+    let decision = if gate == "tty" {
+        tty_decision(c, timeout)?
+    } else if gate.starts_with("http://") {
+        http_decision(&gate, timeout)?
+    } else if gate.starts_with("s3://") {
+        poll(timeout, || s3_decision(c.region.clone(), &gate))
+    } else {
+        poll(timeout, || file_decision(&gate))
+    };
+
+    match decision {
+        Some(true) => {
+            info!("deployment of {} to {} was approved", c.deploy.version, c.deploy.environment);
+            Ok(())
+        },
+        Some(false) => Err("deployment was rejected".to_string()),
+        None => Err(format!("nobody approved the deployment within {} minutes", c.deploy.approval_timeout)),
+    }
+}
+
+fn poll<F>(timeout: time::Duration, f: F) -> Option<bool>
+    where F: Fn() -> Option<bool>
+{
+    let started = time::Instant::now();
+    while started.elapsed() < timeout {
+        if let Some(d) = f() {
+            return Some(d)
+        };
+        thread::sleep(time::Duration::from_secs(10));
+    };
+    None
+}
+
+fn decision_from(body: &str) -> bool {
+    body.trim().to_lowercase() != "reject"
+}
+
+fn file_decision(path: &str) -> Option<bool> {
+    std::fs::read_to_string(path).ok().map(|b| decision_from(&b))
+}
+
+fn s3_decision(r: rusoto_core::Region, location: &str) -> Option<bool> {
+    let uri = location.parse::<Uri>().unwrap();
+    let get_req = GetObjectRequest {
+        bucket: uri.host().unwrap().to_string(),
+        key: uri.path().trim_start_matches('/').to_string(),
+        ..Default::default()
+    };
+
+    let result = S3Client::new(r).get_object(get_req).sync().ok()?;
+    let body: Vec<u8> = result.body?.concat2().wait().ok()?.to_vec();
+    Some(decision_from(&String::from_utf8_lossy(&body)))
+}
+
+/// Lines read from stdin by a single reader thread, started the first time
+/// the terminal is asked.  A reader blocked on stdin cannot be stopped, so
+/// it is shared by every question rather than left behind by a timed-out
+/// one to swallow the next answer.
+fn stdin_lines() -> &'static Mutex<mpsc::Receiver<String>> {
+    static LINES: OnceLock<Mutex<mpsc::Receiver<String>>> = OnceLock::new();
+    LINES.get_or_init(|| {
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let stdin = std::io::stdin();
+            for line in stdin.lock().lines() {
+                let sent = line.ok().map(|l| tx.send(l).is_ok()).unwrap_or(false);
+                if !sent {
+                    break
+                };
+            };
+        });
+        Mutex::new(rx)
+    })
+}
+
+fn tty_decision(c: &DeploymentContext, timeout: time::Duration) -> Result<Option<bool>, String> {
+    if !std::io::stdin().is_terminal() {
+        return Err("approval is set to tty, but stdin is not a terminal; configure another approval gate".to_string())
+    };
+
+    let lines = stdin_lines().lock().unwrap();
+    // Anything typed before the question was asked is not an answer to it.
+    while lines.try_recv().is_ok() {};
+
+    print!("Canary for {} {} in {} looks good. Roll it out? [y/N] ", c.deploy.application, c.deploy.version, c.deploy.environment);
+    let _ = std::io::stdout().flush();
+
+    match lines.recv_timeout(timeout) {
+        Ok(answer) => Ok(Some(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))),
+        Err(mpsc::RecvTimeoutError::Timeout) => Ok(None),
+        Err(mpsc::RecvTimeoutError::Disconnected) => Err("stdin closed while waiting for approval".to_string()),
+    }
+}
+
+/// Listen on the gate's address until `POST /approve` or `POST /reject`
+/// arrives or the timeout passes.
+fn http_decision(gate: &str, timeout: time::Duration) -> Result<Option<bool>, String> {
+    let addr = gate.trim_start_matches("http://").trim_end_matches('/');
+    let listener = TcpListener::bind(addr).map_err(|e| format!("could not listen for approval on {}: {}", addr, e))?;
+    listener.set_nonblocking(true).map_err(|e| format!("{}", e))?;
+
+    let started = time::Instant::now();
+    while started.elapsed() < timeout {
+        let mut stream = match listener.accept() {
+            Ok((s, _)) => s,
+            Err(_) => {
+                thread::sleep(time::Duration::from_millis(500));
+                continue
+            },
+        };
+        let _ = stream.set_nonblocking(false);
+        let _ = stream.set_read_timeout(Some(time::Duration::from_secs(5)));
+
+        let mut request_line = String::new();
+        let _ = BufReader::new(&stream).read_line(&mut request_line);
+        let decision = match request_line.split_whitespace().take(2).collect::<Vec<_>>().as_slice() {
+            ["POST", "/approve"] => Some(true),
+            ["POST", "/reject"] => Some(false),
+            _ => None,
+        };
+
+        let response = match decision {
+            Some(true) => "HTTP/1.1 200 OK\r\nContent-Length: 9\r\nConnection: close\r\n\r\napproved\n",
+            Some(false) => "HTTP/1.1 200 OK\r\nContent-Length: 9\r\nConnection: close\r\n\r\nrejected\n",
+            None => "HTTP/1.1 404 Not Found\r\nContent-Length: 37\r\nConnection: close\r\n\r\nPOST /approve or /reject to decide.\r\n",
+        };
+        let _ = stream.write_all(response.as_bytes());
+
+        if decision.is_some() {
+            return Ok(decision)
+        };
+    };
+
+    Ok(None)
+}
@@ -0,0 +1,335 @@
+use std::collections::HashMap;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Mutex, OnceLock};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+use chrono::{DateTime, Local, SecondsFormat, Utc};
+use futures::{Future, Stream};
+use http::Uri;
+use prettytable::{Table, format};
+use rusoto_s3::{S3, S3Client, GetBucketLocationRequest, GetObjectRequest, ListObjectsV2Request, PutObjectRequest};
+use serde_json::Value;
+use utils::{dry_run, paginate};
+
+/// Where audit records go when the universe does not set `audit_store`.
+pub const DEFAULT_AUDIT_STORE: &str = "burnish-audit.jsonl";
+
+#[derive(Debug, Clone, Default)]
+pub struct AuditRecord {
+    pub action:           std::string::String,
+    pub user:             std::string::String,
+    pub host:             std::string::String,
+    pub command:          std::string::String,
+    pub app:              std::string::String,
+    pub env:              std::string::String,
+    pub region:           std::string::String,
+    pub version:          std::string::String,
+    pub previous_version: std::string::String,
+    pub launch_config:    std::string::String,
+    pub strategy:         std::string::String,
+    pub detail:           std::string::String,
+    pub canary_stats:     Vec<f64>,
+    pub result:           std::string::String,
+    pub started_at:       std::string::String,
+    pub duration_secs:    u64,
+}
+
+/// Start a record for `action`, filling in who is running it, from where,
+/// with which command line, and when.
+pub fn new_record(action: &str) -> AuditRecord {
+    let user = std::env::var("USER")
+        .or_else(|_| std::env::var("USERNAME"))
+        .unwrap_or_else(|_| "unknown".to_string());
+    let host = match hostname::get() {
+        Ok(h) => h.to_string_lossy().to_string(),
+        Err(_) => "unknown".to_string(),
+    };
+
+    AuditRecord {
+        action:     action.to_string(),
+        user,
+        host,
+        command:    std::env::args().collect::<Vec<String>>().join(" "),
+        started_at: Local::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+        ..Default::default()
+    }
+}
+
+/// The store configured in the universe: a local JSONL file or an
+/// `s3://bucket/prefix/` under which each record is written as an object.
+pub fn audit_store(u: &yaml_rust::Yaml) -> String {
+    u["audit_store"].as_str().unwrap_or(DEFAULT_AUDIT_STORE).to_string()
+}
+
+/// Run `f`, then append `record` to the audit store with the outcome and
+/// duration filled in.  Panics from `f` are recorded as failures and then
+/// allowed to continue unwinding.  Dry runs are not audited.
+pub fn audited<F>(r: rusoto_core::Region, u: &yaml_rust::Yaml, mut record: AuditRecord, f: F)
+    where F: FnOnce(&mut AuditRecord)
+{
+    if dry_run() {
+        f(&mut record);
+        return
+    };
+
+    if record.region.is_empty() {
+        record.region = r.name().to_string();
+    };
+    let now = Instant::now();
+    let outcome = panic::catch_unwind(AssertUnwindSafe(|| f(&mut record)));
+
+    record.duration_secs = now.elapsed().as_secs();
+    record.result = match outcome {
+        Ok(_) => "success".to_string(),
+        Err(ref e) => format!("failure: {}", panic_message(&**e)),
+    };
+    append_record(r, &audit_store(u), &record);
+
+    if let Err(e) = outcome {
+        panic::resume_unwind(e);
+    };
+}
+
+/// The message a panic was raised with.
+pub fn panic_message(e: &(dyn std::any::Any + Send)) -> String {
+    if let Some(s) = e.downcast_ref::<String>() {
+        return s.clone()
+    };
+    if let Some(s) = e.downcast_ref::<&str>() {
+        return s.to_string()
+    };
+    "unknown error".to_string()
+}
+
+pub fn record_to_json(a: &AuditRecord) -> Value {
+    json!({
+        "action": a.action,
+        "user": a.user,
+        "host": a.host,
+        "command": a.command,
+        "app": a.app,
+        "env": a.env,
+        "region": a.region,
+        "version": a.version,
+        "previous_version": a.previous_version,
+        "launch_config": a.launch_config,
+        "strategy": a.strategy,
+        "detail": a.detail,
+        "canary_stats": a.canary_stats,
+        "result": a.result,
+        "started_at": a.started_at,
+        "duration_secs": a.duration_secs,
+    })
+}
+
+pub fn record_from_json(v: &Value) -> AuditRecord {
+    let s = |k: &str| v[k].as_str().unwrap_or("").to_string();
+    AuditRecord {
+        action:           s("action"),
+        user:             s("user"),
+        host:             s("host"),
+        command:          s("command"),
+        app:              s("app"),
+        env:              s("env"),
+        region:           s("region"),
+        version:          s("version"),
+        previous_version: s("previous_version"),
+        launch_config:    s("launch_config"),
+        strategy:         s("strategy"),
+        detail:           s("detail"),
+        canary_stats:     v["canary_stats"].as_array().map(|a| a.iter().filter_map(|x| x.as_f64()).collect()).unwrap_or_default(),
+        result:           s("result"),
+        started_at:       s("started_at"),
+        duration_secs:    v["duration_secs"].as_u64().unwrap_or(0),
+    }
+}
+
+/// Append a record to the store.  Failing to write the audit trail is
+/// reported but never fails the operation being audited.
+pub fn append_record(r: rusoto_core::Region, store: &str, a: &AuditRecord) {
+    let line = record_to_json(a).to_string();
+
+    if store.starts_with("s3://") {
+        let (bucket, prefix) = s3_location(store);
+        let s3_client = S3Client::new(bucket_region(&r, &bucket));
+        let put_req = PutObjectRequest {
+            bucket,
+            key: record_key(&prefix, a),
+            body: Some(line.into_bytes().into()),
+            ..Default::default()
+        };
+        match s3_client.put_object(put_req).sync() {
+            Ok(_k) => info!("audit record written to {}", store),
+            Err(error) => eprintln!("ERROR: could not write audit record: {:?}", error),
+        };
+    } else {
+        let written = OpenOptions::new().create(true).append(true).open(store)
+            .and_then(|mut f| writeln!(f, "{}", line));
+        match written {
+            Ok(_k) => info!("audit record written to {}", store),
+            Err(error) => eprintln!("ERROR: could not write audit record: {:?}", error),
+        };
+    };
+}
+
+/// The S3 key for record `a` under `prefix`.  Regions deployed in parallel
+/// start in the same second, so the key holds the region and a suffix
+/// unique to this write as well as the start time.
+fn record_key(prefix: &str, a: &AuditRecord) -> String {
+    static WRITES: AtomicUsize = AtomicUsize::new(0);
+    let unique = format!("{}.{:09}.{}", std::process::id(), Utc::now().timestamp_subsec_nanos(),
+                         WRITES.fetch_add(1, Ordering::SeqCst));
+    format!("{}{}/{}/{}-{}-{}-{}.json", prefix, a.app, a.env, a.started_at, a.region, a.action, unique)
+}
+
+/// Every record for `app` in `env`, oldest first.
+pub fn read_records(r: rusoto_core::Region, store: &str, app: &str, env: &str) -> Vec<AuditRecord> {
+    let mut lines: Vec<String> = Vec::new();
+
+    if store.starts_with("s3://") {
+        let (bucket, prefix) = s3_location(store);
+        let s3_client = S3Client::new(bucket_region(&r, &bucket));
+        let objects = paginate(|token| {
+            let list_req = ListObjectsV2Request {
+                bucket: bucket.clone(),
+                prefix: Some(format!("{}{}/{}/", prefix, app, env)),
+                continuation_token: token,
+                ..Default::default()
+            };
+            let listing = s3_client.list_objects_v2(list_req).sync().expect("Couldn't list audit records in S3");
+            (listing.contents.unwrap_or_default(), listing.next_continuation_token)
+        });
+
+        for o in objects {
+            let get_req = GetObjectRequest {
+                bucket: bucket.clone(),
+                key: o.key.unwrap(),
+                ..Default::default()
+            };
+            let result = s3_client.get_object(get_req).sync().expect("Couldn't GET audit record from S3");
+            let body: Vec<u8> = result.body.unwrap().concat2().wait().unwrap().to_vec();
+            lines.push(String::from_utf8_lossy(&body).to_string());
+        };
+    } else if let Ok(f) = std::fs::read_to_string(store) {
+        lines = f.lines().map(|l| l.to_string()).collect();
+    };
+
+    let mut records: Vec<AuditRecord> = lines.iter()
+        .filter_map(|l| serde_json::from_str::<Value>(l).ok())
+        .map(|v| record_from_json(&v))
+        .filter(|a| a.app == app && a.env == env)
+        .collect();
+    // Records carry the offset of whoever wrote them, so they are ordered by
+    // the instant they started rather than as strings.
+    records.sort_by_key(|a| DateTime::parse_from_rfc3339(&a.started_at).ok());
+    records
+}
+
+fn s3_location(store: &str) -> (String, String) {
+    let uri = store.parse::<Uri>().unwrap();
+    let mut prefix = uri.path().trim_start_matches('/').to_string();
+    if !prefix.is_empty() && !prefix.ends_with('/') {
+        prefix.push('/');
+    };
+    (uri.host().unwrap().to_string(), prefix)
+}
+
+/// The region audit bucket `bucket` is in, looked up once per run.  A
+/// deployment to several regions writes its records from each of them, so
+/// the deploy region cannot be assumed.  Falls back to `r` if the lookup
+/// fails.
+fn bucket_region(r: &rusoto_core::Region, bucket: &str) -> rusoto_core::Region {
+    static REGIONS: OnceLock<Mutex<HashMap<String, rusoto_core::Region>>> = OnceLock::new();
+    let mut regions = REGIONS.get_or_init(Default::default).lock().unwrap();
+
+    regions.entry(bucket.to_string()).or_insert_with(|| {
+        let req = GetBucketLocationRequest { bucket: bucket.to_string() };
+        match S3Client::new(r.clone()).get_bucket_location(req).sync() {
+            // Buckets in us-east-1 have no location constraint, and old ones
+            // in eu-west-1 have `EU`.
+            Ok(res) => match res.location_constraint.as_deref() {
+                None | Some("") => rusoto_core::Region::UsEast1,
+                Some("EU") => rusoto_core::Region::EuWest1,
+                Some(l) => l.parse().unwrap_or_else(|_| r.clone()),
+            },
+            Err(error) => {
+                warn!("WARN: could not find the region of audit bucket {}, using {}: {:?}", bucket, r.name(), error);
+                r.clone()
+            },
+        }
+    }).clone()
+}
+
+pub fn history_cmd(r: rusoto_core::Region, m: &clap::ArgMatches, u: yaml_rust::Yaml) {
+    let mut records = read_records(r, &audit_store(&u), m.value_of("app").unwrap(), m.value_of("env").unwrap());
+    records.reverse();
+    if let Some(l) = m.value_of("limit") {
+        records.truncate(l.parse::<usize>().unwrap());
+    };
+
+    let mut table = Table::new();
+    table.set_format(*format::consts::FORMAT_NO_LINESEP_WITH_TITLE);
+    table.set_titles(row!["Started", "User", "Host", "Region", "Action", "Version", "Previous", "Launch Config", "Strategy", "Result", "Duration"]);
+
+    for a in records {
+        let started = match DateTime::parse_from_rfc3339(&a.started_at) {
+            Ok(dt) => {
+                let mut dt_f = timeago::Formatter::new();
+                dt_f.num_items(2);
+                dt_f.convert(Local::now().signed_duration_since(dt).to_std().unwrap_or_default())
+            },
+            Err(_) => a.started_at.clone(),
+        };
+        table.add_row(row![
+            started,
+            a.user,
+            a.host,
+            a.region,
+            a.action,
+            a.version,
+            a.previous_version,
+            a.launch_config,
+            a.strategy,
+            a.result,
+            format!("{}s", a.duration_secs)
+        ]);
+    };
+    table.printstd();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(region: &str) -> AuditRecord {
+        AuditRecord {
+            action:     "deploy".to_string(),
+            app:        "web".to_string(),
+            env:        "prod".to_string(),
+            region:     region.to_string(),
+            started_at: "2026-10-19T09:00:00+02:00".to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn records_keep_their_region() {
+        let a = record("eu-west-1");
+        assert_eq!(record_from_json(&record_to_json(&a)).region, "eu-west-1");
+    }
+
+    #[test]
+    fn keys_differ_between_regions_started_together() {
+        let east = record_key("audit/", &record("us-east-1"));
+        let west = record_key("audit/", &record("us-west-2"));
+        assert!(east.starts_with("audit/web/prod/2026-10-19T09:00:00+02:00-us-east-1-deploy-"));
+        assert_ne!(east, west);
+    }
+
+    #[test]
+    fn keys_differ_for_the_same_region() {
+        assert_ne!(record_key("", &record("us-east-1")), record_key("", &record("us-east-1")));
+    }
+}
@@ -1,57 +1,106 @@
-use std::process;
+use std::cmp;
+use std::fmt;
+use std::collections::{BTreeMap, VecDeque};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+use chrono::{DateTime, Local};
 use prettytable::{Table, format};
 use rusoto_autoscaling::{
-    Autoscaling, AutoscalingClient, AutoScalingGroupNamesType, ScalingProcessQuery,
-    CreateAutoScalingGroupType, DeleteAutoScalingGroupType, UpdateAutoScalingGroupType,
-    CreateOrUpdateTagsType, TerminateInstanceInAutoScalingGroupType, Instance, Tag,
+    Activity, Autoscaling, AutoscalingClient, AutoScalingGroupNamesType, DescribeScalingActivitiesType, ScalingProcessQuery,
+    CreateAutoScalingGroupType, DeleteAutoScalingGroupType, UpdateAutoScalingGroupType, AttachLoadBalancersType, DetachLoadBalancersType,
+    CreateOrUpdateTagsType, TerminateInstanceInAutoScalingGroupType, Instance, LaunchTemplateSpecification, Tag,
 };
 
-use loadbalancer::wait_for_in_service;
+use rusoto_ec2::{Ec2, Ec2Client, DescribeLaunchTemplatesRequest, DescribeSubnetsRequest};
+
+use loadbalancer::in_service_ids;
+use processes::{resume_expired_suspension, suspension_description};
+use refresh::{cancel_instance_refresh, refresh_calls, refresh_instances, rollback_instance_refresh};
+use snapshot::{keep_snapshot, restore_snapshot};
+use utils::{aws_call, describe_instances, dry_run, paginate, skip_for_dry_run};
+use audit::{AuditRecord, audited, new_record};
 
 #[derive(Debug, Clone)]
 pub struct AutoScaleGroup {
-    name:                 std::string::String,
-    min_size:             i64,
-    max_size:             i64,
+    pub name:             std::string::String,
+    pub min_size:         i64,
+    pub max_size:         i64,
     pub desired_capacity: i64,
     pub instance_count:   i64,
     pub lc_name:          std::string::String,
-    app_name:             std::string::String,
-    env_name:             std::string::String,
+    pub launch_template:  Option<LaunchTemplateSpecification>,
+    pub version:          std::string::String,
+    pub instances:        Vec<Instance>,
+    pub elbs:             Vec<std::string::String>,
+    pub suspended_processes: Vec<std::string::String>,
+    pub availability_zones: Vec<std::string::String>,
+    pub subnets:          Vec<std::string::String>,
+    pub tags:             Vec<Tag>,
+    pub app_name:         std::string::String,
+    pub env_name:         std::string::String,
+}
+
+impl AutoScaleGroup {
+    /// The value of the group's `key` tag, if it has one.
+    pub fn tag(&self, key: &str) -> Option<&str> {
+        self.tags.iter().find(|t| t.key == key).and_then(|t| t.value.as_deref())
+    }
 }
 
 pub fn list_asg(r: rusoto_core::Region, n: String) -> Vec<AutoScaleGroup> {
     let as_client = AutoscalingClient::new(r.to_owned());
-    let mut asg_req = AutoScalingGroupNamesType {
-        ..Default::default()
-    };
-
-    if n != "" {
-		asg_req.auto_scaling_group_names = Some(vec![n])
-	}
-
-    let asg_results = as_client.describe_auto_scaling_groups(asg_req).sync().ok();
+    let asg_results = paginate(|token| {
+        let asg_req = AutoScalingGroupNamesType {
+            auto_scaling_group_names: if n != "" { Some(vec![n.clone()]) } else { None },
+            next_token:               token,
+            ..Default::default()
+        };
+        match as_client.describe_auto_scaling_groups(asg_req).sync() {
+            Ok(res) => (res.auto_scaling_groups, res.next_token),
+            Err(error) => panic!("ERROR: {:?}", error),
+        }
+    });
 
-    let mut app_name = String::new();
-    let mut env_name = String::new();
     let mut scaling_groups: Vec<AutoScaleGroup> = Vec::new();
 
-    for asg in asg_results.unwrap().auto_scaling_groups {
-        for t in asg.tags.unwrap() {
+    for asg in asg_results {
+        let mut app_name = String::new();
+        let mut env_name = String::new();
+        let mut version = String::new();
+        let mut tags = Vec::new();
+        for t in asg.tags.unwrap_or_default() {
             if t.key.clone().unwrap() == "app".to_string() {
-				app_name = t.value.unwrap()
+				app_name = t.value.clone().unwrap()
 			} else if t.key.clone().unwrap() == "env".to_string() {
-				env_name = t.value.unwrap()
+				env_name = t.value.clone().unwrap()
+			} else if t.key.clone().unwrap() == "version".to_string() {
+				version = t.value.clone().unwrap()
 			};
+            tags.push(Tag {
+                key:                 t.key.unwrap_or_default(),
+                propagate_at_launch: t.propagate_at_launch,
+                resource_id:         t.resource_id,
+                resource_type:       t.resource_type,
+                value:               t.value,
+            });
         };
 
+		let instances = asg.instances.unwrap_or_default();
 		let scaling_group = AutoScaleGroup {
 			name:             asg.auto_scaling_group_name,
 			min_size:         asg.min_size,
 			max_size:         asg.max_size,
 			desired_capacity: asg.desired_capacity,
-			instance_count:   asg.instances.unwrap().len() as i64,
-			lc_name:          asg.launch_configuration_name.unwrap(),
+			instance_count:   instances.len() as i64,
+			lc_name:          asg.launch_configuration_name.unwrap_or_default(),
+			launch_template:  asg.launch_template,
+			version,
+			instances,
+			elbs:             asg.load_balancer_names.unwrap_or_default(),
+			suspended_processes: asg.suspended_processes.unwrap_or_default().into_iter().filter_map(|p| p.process_name).collect(),
+			availability_zones: asg.availability_zones,
+			subnets:          asg.vpc_zone_identifier.unwrap_or_default().split(',').filter(|s| !s.is_empty()).map(|s| s.to_string()).collect(),
+			tags,
 			app_name:         app_name.clone(),
 			env_name:         env_name.clone(),
 		};
@@ -61,25 +110,176 @@ pub fn list_asg(r: rusoto_core::Region, n: String) -> Vec<AutoScaleGroup> {
     return scaling_groups
 }
 
+/// ASG `n`.  Panics if there is no such group.
+pub fn current_asg(r: rusoto_core::Region, n: &str) -> AutoScaleGroup {
+    match list_asg(r, n.to_string()).into_iter().find(|a| a.name == n) {
+        Some(a) => a,
+        None => panic!("ERROR: autoscaling group {} could not be found.", n),
+    }
+}
+
 pub fn list_asg_cmd(r: rusoto_core::Region, m: &clap::ArgMatches) {
-    let results = list_asg(r, m.value_of("name").unwrap().to_string());
+    let apps: Vec<&str> = m.values_of("app").map(|v| v.collect()).unwrap_or_default();
+    let envs: Vec<&str> = m.values_of("env").map(|v| v.collect()).unwrap_or_default();
+    let results = list_asg(r.clone(), String::new()).into_iter()
+        .filter(|a| apps.is_empty() || apps.contains(&a.app_name.as_str()))
+        .filter(|a| envs.is_empty() || envs.contains(&a.env_name.as_str()));
 
     let mut table = Table::new();
     table.set_format(*format::consts::FORMAT_NO_BORDER_LINE_SEPARATOR);
-    table.set_titles(row!["ASG Name", "Instance Count (Current)", "Min Size", "Max Size"]);
+    table.set_titles(row!["ASG Name", "Instance Count (Current)", "Min Size", "Max Size", "Desired",
+        "Launch Config", "Version", "ELBs", "Suspended Processes", "Health"]);
 
     for asg in results {
         table.add_row(row![
             format!("{}", asg.name),
             format!("{}", asg.instance_count),
             format!("{}", asg.min_size),
-            format!("{}", asg.max_size)
+            format!("{}", asg.max_size),
+            format!("{}", asg.desired_capacity),
+            launch_description(&asg),
+            asg.version,
+            asg.elbs.join(", "),
+            suspension_description(&asg),
+            health_counts(&asg)
         ]);
     };
     table.printstd();
 }
 
+pub fn show_asg_cmd(r: rusoto_core::Region, m: &clap::ArgMatches) {
+    let name = m.value_of("name").unwrap();
+    let activities = m.value_of("activities").unwrap().parse::<i64>().unwrap();
+
+    if !m.is_present("watch") {
+        show_asg(r, name, activities);
+        return
+    };
+
+    let interval = m.value_of("interval").unwrap().parse::<u64>().unwrap();
+    loop {
+        print!("\x1B[2J\x1B[H");
+        show_asg(r.clone(), name, activities);
+        println!();
+        println!("Refreshing every {} seconds; press Ctrl-C to stop.", interval);
+        sleep(Duration::from_secs(interval));
+    };
+}
+
+/// Print the configuration of ASG `n`, each of its instances, and its
+/// `activities` most recent scaling activities.
+pub fn show_asg(r: rusoto_core::Region, n: &str, activities: i64) {
+    let asg = current_asg(r.clone(), n);
+
+    let mut table = Table::new();
+    table.set_format(*format::consts::FORMAT_CLEAN);
+    table.add_row(row!["Name", asg.name]);
+    table.add_row(row!["App/Env", format!("{}/{}", asg.app_name, asg.env_name)]);
+    table.add_row(row!["Min/Max/Desired", format!("{}/{}/{}", asg.min_size, asg.max_size, asg.desired_capacity)]);
+    table.add_row(row!["Launch Config", launch_description(&asg)]);
+    table.add_row(row!["Version", asg.version]);
+    table.add_row(row!["ELBs", asg.elbs.join(", ")]);
+    table.add_row(row!["Availability Zones", asg.availability_zones.join(", ")]);
+    table.add_row(row!["Subnets", asg.subnets.join(", ")]);
+    table.add_row(row!["Suspended Processes", suspension_description(&asg)]);
+    table.add_row(row!["Health", health_counts(&asg)]);
+    table.printstd();
+    println!();
+
+    let ids: Vec<String> = asg.instances.iter().map(|i| i.instance_id.clone()).collect();
+    let described = describe_instances(r.clone(), &ids);
+
+    let mut table = Table::new();
+    table.set_format(*format::consts::FORMAT_NO_LINESEP_WITH_TITLE);
+    table.set_titles(row!["Instance ID", "Lifecycle State", "Health", "Availability Zone", "Launched From", "Age"]);
+    for i in &asg.instances {
+        let launched = described.iter()
+            .find(|d| d.instance_id.as_ref() == Some(&i.instance_id))
+            .and_then(|d| d.launch_time.clone())
+            .unwrap_or_default();
+        table.add_row(row![
+            i.instance_id,
+            i.lifecycle_state,
+            i.health_status,
+            i.availability_zone,
+            launched_from(i),
+            ago(&launched)
+        ]);
+    };
+    table.printstd();
+    println!();
+
+    let mut table = Table::new();
+    table.set_format(*format::consts::FORMAT_NO_LINESEP_WITH_TITLE);
+    table.set_titles(row!["Started", "Status", "Description", "Cause"]);
+    for a in scaling_activities(r, n, activities) {
+        let status = match a.status_message {
+            Some(ref msg) if !msg.is_empty() => format!("{}: {}", a.status_code, msg),
+            _ => a.status_code.clone(),
+        };
+        table.add_row(row![
+            ago(&a.start_time),
+            status,
+            a.description.clone().unwrap_or_default(),
+            a.cause
+        ]);
+    };
+    table.printstd();
+}
+
+/// The `max` most recent scaling activities of `n`, newest first.
+pub fn scaling_activities(r: rusoto_core::Region, n: &str, max: i64) -> Vec<Activity> {
+    let as_client = AutoscalingClient::new(r.to_owned());
+    let req = DescribeScalingActivitiesType {
+        auto_scaling_group_name: Some(n.to_string()),
+        max_records:             Some(max),
+        ..Default::default()
+    };
+
+    match as_client.describe_scaling_activities(req).sync() {
+        Ok(res) => res.activities,
+        Err(error) => panic!("ERROR: {:?}", error),
+    }
+}
+
+/// How long ago the RFC 3339 time `t` was, such as `3 hours 12 minutes`.
+fn ago(t: &str) -> String {
+    match DateTime::parse_from_rfc3339(t) {
+        Ok(dt) => {
+            let mut dt_f = timeago::Formatter::new();
+            dt_f.num_items(2);
+            dt_f.convert(Local::now().signed_duration_since(dt).to_std().unwrap_or_default())
+        },
+        Err(_) => t.to_string(),
+    }
+}
+
+/// What new instances of `asg` are launched from: its launch configuration,
+/// or its launch template and version.
+pub fn launch_description(asg: &AutoScaleGroup) -> String {
+    match asg.launch_template {
+        Some(ref t) => format!("{} ({})",
+            t.launch_template_name.clone().or_else(|| t.launch_template_id.clone()).unwrap_or_default(),
+            t.version.clone().unwrap_or_else(|| "$Default".to_string())),
+        None => asg.lc_name.clone(),
+    }
+}
+
+/// How many instances of `asg` are in each health status, such as
+/// `3 Healthy, 1 Unhealthy`.
+pub fn health_counts(asg: &AutoScaleGroup) -> String {
+    let mut counts: BTreeMap<&str, usize> = BTreeMap::new();
+    for i in &asg.instances {
+        *counts.entry(i.health_status.as_str()).or_insert(0) += 1;
+    };
+    counts.iter().map(|(s, n)| format!("{} {}", n, s)).collect::<Vec<_>>().join(", ")
+}
+
 pub fn create_asg(r: rusoto_core::Region, t: CreateAutoScalingGroupType) {
+    if skip_for_dry_run("autoscaling", "CreateAutoScalingGroup", format!("{} (launch config: {})", t.auto_scaling_group_name, t.launch_configuration_name.clone().unwrap_or_default())) {
+        return
+    };
+
     let as_client = AutoscalingClient::new(r.to_owned());
 
     match as_client.create_auto_scaling_group(t.clone()).sync() {
@@ -188,6 +388,10 @@ pub fn create_asg_cmd(r: rusoto_core::Region, m: &clap::ArgMatches, u: yaml_rust
 }
 
 pub fn destroy_asg(r: rusoto_core::Region, n: String, b: bool) {
+    if skip_for_dry_run("autoscaling", "DeleteAutoScalingGroup", format!("{} (force: {})", n, b)) {
+        return
+    };
+
     let as_client = AutoscalingClient::new(r.to_owned());
     let asg_req = DeleteAutoScalingGroupType {
         auto_scaling_group_name: n.clone(),
@@ -206,6 +410,10 @@ pub fn destroy_asg_cmd(r: rusoto_core::Region, m: &clap::ArgMatches) {
 }
 
 pub fn resize_asg(r: rusoto_core::Region, n: String, min: i64, max: i64, d: i64) {
+    if skip_for_dry_run("autoscaling", "UpdateAutoScalingGroup", format!("{} (min: {}, max: {}, desired: {})", n, min, max, d)) {
+        return
+    };
+
     let as_client = AutoscalingClient::new(r.to_owned());
     let asg_req = UpdateAutoScalingGroupType {
         auto_scaling_group_name: n.clone(),
@@ -221,166 +429,673 @@ pub fn resize_asg(r: rusoto_core::Region, n: String, min: i64, max: i64, d: i64)
     };
 }
 
-pub fn resize_asg_cmd(r: rusoto_core::Region, m: &clap::ArgMatches) {
-    resize_asg(
-        r, 
-        m.value_of("name").unwrap().to_string(),
-        m.value_of("min").unwrap().parse().unwrap(),
-        m.value_of("max").unwrap().parse().unwrap(),
-        m.value_of("desired").unwrap().parse().unwrap(),
-    );
+/// Attach ELB `elb` to ASG `n`, registering its instances with it.
+pub fn attach_elb(r: rusoto_core::Region, n: &str, elb: &str) {
+    if skip_for_dry_run("autoscaling", "AttachLoadBalancers", format!("{} ({})", n, elb)) {
+        return
+    };
+
+    let as_client = AutoscalingClient::new(r.to_owned());
+    let req = AttachLoadBalancersType {
+        auto_scaling_group_name: n.to_string(),
+        load_balancer_names:     vec![elb.to_string()],
+    };
+    match as_client.attach_load_balancers(req).sync() {
+        Ok(_k) => info!("attached {} to {}", elb, n),
+        Err(error) => panic!("ERROR: {:?}", error),
+    };
 }
 
-pub fn rotate_instances(r: rusoto_core::Region, n: String, b: usize) {
+/// Detach ELB `elb` from ASG `n`, deregistering its instances from it.  The
+/// instances themselves keep running.
+pub fn detach_elb(r: rusoto_core::Region, n: &str, elb: &str) {
+    if skip_for_dry_run("autoscaling", "DetachLoadBalancers", format!("{} ({})", n, elb)) {
+        return
+    };
+
     let as_client = AutoscalingClient::new(r.to_owned());
-    let mut asg_req = AutoScalingGroupNamesType {
-        ..Default::default()
+    let req = DetachLoadBalancersType {
+        auto_scaling_group_name: n.to_string(),
+        load_balancer_names:     vec![elb.to_string()],
+    };
+    match as_client.detach_load_balancers(req).sync() {
+        Ok(_k) => info!("detached {} from {}", elb, n),
+        Err(error) => panic!("ERROR: {:?}", error),
     };
+}
 
-    if n != "" {
-		asg_req.auto_scaling_group_names = Some(vec![n.clone()])
-	}
+pub fn resize_asg_cmd(r: rusoto_core::Region, m: &clap::ArgMatches, u: yaml_rust::Yaml) {
+    let name = m.value_of("name").unwrap().to_string();
+    let min: i64 = m.value_of("min").unwrap().parse().unwrap();
+    let max: i64 = m.value_of("max").unwrap().parse().unwrap();
+    let desired: i64 = m.value_of("desired").unwrap().parse().unwrap();
+
+    audited(r.clone(), &u, asg_record("resize", r.clone(), &name), |a| {
+        a.detail = format!("{} min/max/desired {} -> {}/{}/{}", name, a.detail, min, max, desired);
+        keep_snapshot(&r, &current_asg(r.clone(), &name));
+        resize_asg(r.clone(), name.clone(), min, max, desired);
+    });
+}
 
-    let list_result = as_client.describe_auto_scaling_groups(asg_req).sync().ok().unwrap().auto_scaling_groups;
+/// Start an audit record for an operation on a single ASG, filled in from
+/// the group's tags and current state.  Dry runs are not audited, so the
+/// group is not looked up for them.
+pub fn asg_record(action: &str, r: rusoto_core::Region, n: &str) -> AuditRecord {
+    let mut record = new_record(action);
+    if dry_run() {
+        return record
+    };
 
-    if list_result.len() == 0 {
-        panic!("ERROR: autoscaling group {} could not be found.", n.clone().to_string());
+    if let Some(asg) = list_asg(r, n.to_string()).into_iter().next() {
+        record.app = asg.app_name;
+        record.env = asg.env_name;
+        record.version = asg.version.clone();
+        record.previous_version = asg.version;
+        record.launch_config = asg.lc_name;
+        record.detail = format!("{}/{}/{}", asg.min_size, asg.max_size, asg.desired_capacity);
+    };
+    record
+}
+
+/// Processes that must be running for a rotation to work.
+const REQUIRED_PROCESSES: &[&str] = &["RemoveFromLoadBalancerLowPriority", "Terminate", "Launch", "HealthCheck", "AddToLoadBalancer"];
+
+/// Processes suspended while a rotation runs, so nothing else scales or
+/// replaces instances underneath it.
+const ROTATION_PROCESSES: &[&str] = &["ReplaceUnhealthy", "AlarmNotification", "ScheduledActions", "AZRebalance"];
+
+/// Seconds to wait for each batch's new instances to come InService.
+const BATCH_TIMEOUT: u64 = 60 * 15;
+
+/// Times a rotation goes back for outdated instances that appeared while it
+/// ran before giving up.
+const MAX_PASSES: usize = 3;
+
+/// The fraction of its instances each availability zone keeps while a batch
+/// is replaced, unless told otherwise.
+pub const DEFAULT_MIN_AZ_CAPACITY: f64 = 0.5;
+
+/// A number of instances: a count, or a percentage of an ASG's desired
+/// capacity.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Amount {
+    Count(usize),
+    Percent(f64),
+}
+
+impl Amount {
+    /// Parse a count such as `2` or a percentage such as `25%`.
+    pub fn parse(s: &str) -> Result<Amount, String> {
+        let s = s.trim();
+        match s.strip_suffix('%') {
+            Some(p) => match p.trim().parse::<f64>() {
+                Ok(p) if (0.0..=100.0).contains(&p) => Ok(Amount::Percent(p)),
+                _ => Err(format!("{} is not a percentage between 0% and 100%", s)),
+            },
+            None => s.parse::<usize>().map(Amount::Count).map_err(|_| format!("{} is not a count or a percentage", s)),
+        }
     }
 
-    if list_result.len() > 1 {
-        panic!("ERROR: more than one autoscaling group named {} was found.", n.clone().to_string());
+    /// The number of instances this is for a group of `desired` instances,
+    /// rounding a percentage up or down.
+    pub fn resolve(&self, desired: i64, round_up: bool) -> usize {
+        match *self {
+            Amount::Count(n) => n,
+            Amount::Percent(p) => {
+                let n = p / 100.0 * cmp::max(desired, 0) as f64;
+                if round_up { (n - 1e-9).ceil() as usize } else { (n + 1e-9).floor() as usize }
+            },
+        }
     }
+}
 
-    let asg = list_result.clone();
-    let initial_max = asg[0].max_size as usize;
-    let initial_desired = asg[0].desired_capacity as usize;
-
-    for (i, sp) in asg[0].suspended_processes.iter().enumerate() {
-        let pn = sp[i].clone();
-        match pn.process_name.unwrap().as_str() {
-            "RemoveFromLoadBalancerLowPriority" | "Terminate" | "Launch" | "HealthCheck" | "AddToLoadBalancer" => {
-                panic!("ERROR: {} has a suspended process that must be active", n.clone().to_string());
-            }
-            _ => ()
+impl fmt::Display for Amount {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Amount::Count(n) => write!(f, "{}", n),
+            Amount::Percent(p) => write!(f, "{}%", p),
         }
     }
+}
 
-    info!("verified {} has correct processes in place", n.clone().to_string());
+/// How to rotate an ASG's instances.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RotationOptions {
+    /// Instances a batch may add above the group's desired capacity.
+    pub max_surge:       Amount,
+    /// Instances a batch may take below the group's desired capacity.
+    pub max_unavailable: Amount,
+    /// The fraction of its InService instances each availability zone keeps
+    /// while a batch is replaced.  At least one instance per zone may always
+    /// be replaced, however small the zone.
+    pub min_az_capacity: f64,
+    /// Replace instances with the group's native instance refresh instead
+    /// of batch by batch.
+    pub refresh:         bool,
+}
 
-    let process_req = ScalingProcessQuery {
-        auto_scaling_group_name: n.clone(),
-        scaling_processes: Some(vec![
-            "ReplaceUnhealthy".to_string(),
-		    "AlarmNotification".to_string(),
-		    "ScheduledActions".to_string(),
-		    "AZRebalance".to_string(),
-        ]),
-    };
+impl RotationOptions {
+    /// The surge and unavailability, in instances, for a group of `desired`
+    /// instances.  As in Kubernetes, the surge rounds up and unavailability
+    /// down.  A batch always replaces at least one instance.
+    pub fn resolve(&self, desired: i64) -> (usize, usize) {
+        match (self.max_surge.resolve(desired, true), self.max_unavailable.resolve(desired, false)) {
+            (0, 0) => (1, 0),
+            sizes => sizes,
+        }
+    }
+}
 
-    match as_client.suspend_processes(process_req.clone()).sync() {
-        Ok(_k) => info!("temporarily suspended ReplaceUnhealthy AlarmNotification ScheduledActions AZRebalance processes for {}", n.clone()),
-        Err(error) => panic!("ERROR: {:?}", error),
-    };
+/// The surge and unavailability given by `--batch`, `--max-surge` and
+/// `--max-unavailable`.  With neither of the last two, each batch surges by
+/// `batch` instances; with either, the other defaults to zero.
+pub fn batch_sizes(batch: usize, max_surge: Option<Amount>, max_unavailable: Option<Amount>) -> (Amount, Amount) {
+    match (max_surge, max_unavailable) {
+        (None, None) => (Amount::Count(batch), Amount::Count(0)),
+        (s, u) => (s.unwrap_or(Amount::Count(0)), u.unwrap_or(Amount::Count(0))),
+    }
+}
+
+/// The ways `rotate_instances` can replace instances, by `--method` name.
+pub const ROTATION_METHODS: &[&str] = &["manual", "refresh"];
+
+/// One batch of a rotation: raise the group's desired capacity to `desired`,
+/// wait until every attached ELB has that many of the group's instances
+/// InService, then terminate `terminate`, lowering the desired capacity by
+/// one for each.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RotationBatch {
+    pub terminate: Vec<String>,
+    pub desired:   i64,
+}
 
-    let instances = asg[0].instances.clone().unwrap();
-    if instances.len() == 0 {
-        warn!("WARN: {} has no instances to rotate", n.clone().to_string());
-        process::exit(0x0100);
+/// Everything `rotate_instances` will do to an ASG, worked out before any of
+/// it is done.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RotationPlan {
+    pub asg:         std::string::String,
+    pub elbs:        Vec<std::string::String>,
+    pub desired:     i64,
+    pub max:         i64,
+    /// The max size during the rotation, raised if a batch needs the room.
+    pub surge_max:   i64,
+    /// Instances each batch adds above, and takes below, `desired`.
+    pub surge:       usize,
+    pub unavailable: usize,
+    pub batches:     Vec<RotationBatch>,
+}
+
+impl RotationPlan {
+    /// The highest desired capacity the group reaches during the rotation.
+    pub fn peak_desired(&self) -> i64 {
+        self.batches.iter().map(|b| b.desired).max().unwrap_or(self.desired)
     }
+}
+
+/// Plan the replacement of `instances` of `asg`, up to the resolved
+/// `o.max_surge` plus `o.max_unavailable` at a time.  Batches take instances
+/// from each availability zone in turn, and no batch takes so many from one
+/// zone that it drops below `o.min_az_capacity`.  Each batch adds up to the
+/// surge before terminating, so the group never drops more than the
+/// unavailable count below its desired capacity.
+pub fn plan_rotation(asg: &AutoScaleGroup, instances: &[String], o: &RotationOptions) -> RotationPlan {
+    let (surge, unavailable) = o.resolve(asg.desired_capacity);
+    let mut by_zone: BTreeMap<String, VecDeque<String>> = BTreeMap::new();
+    for id in instances {
+        let zone = asg.instances.iter().find(|i| &i.instance_id == id).map(|i| i.availability_zone.clone()).unwrap_or_default();
+        by_zone.entry(zone).or_default().push_back(id.clone());
+    };
+
+    let limits: Vec<usize> = by_zone.keys().map(|z| {
+        let n = asg.instances.iter().filter(|i| i.lifecycle_state == "InService" && &i.availability_zone == z).count();
+        cmp::max(1, ((1.0 - o.min_az_capacity) * n as f64 + 1e-9).floor() as usize)
+    }).collect();
+    let mut queues: Vec<VecDeque<String>> = by_zone.into_values().collect();
+
+    let mut batches = Vec::new();
+    let mut next = 0;
+    while queues.iter().any(|q| !q.is_empty()) {
+        let mut terminate = Vec::new();
+        let mut taken = vec![0; queues.len()];
+        let mut idle = 0;
+
+        while terminate.len() < surge + unavailable && idle < queues.len() {
+            let z = next % queues.len();
+            next += 1;
+            if taken[z] < limits[z] {
+                if let Some(id) = queues[z].pop_front() {
+                    terminate.push(id);
+                    taken[z] += 1;
+                    idle = 0;
+                    continue
+                };
+            };
+            idle += 1;
+        };
 
-    let mut instances_to_terminate: Vec<Instance> = Vec::new();
-    for i in instances {
-		if i.lifecycle_state != "InService" {
-			info!("ignoring instance {} lifecycle status is: {}", i.instance_id, i.lifecycle_state);
-		} else {
-			instances_to_terminate.push(i);
-		}
+        batches.push(RotationBatch { desired: asg.desired_capacity + cmp::min(surge, terminate.len()) as i64, terminate });
+    };
+    let peak = batches.iter().map(|b| b.desired).max().unwrap_or(asg.desired_capacity);
+
+    RotationPlan {
+        asg:         asg.name.clone(),
+        elbs:        asg.elbs.clone(),
+        desired:     asg.desired_capacity,
+        max:         asg.max_size,
+        surge_max:   cmp::max(asg.max_size, peak),
+        surge,
+        unavailable,
+        batches,
     }
+}
+
+/// The availability zones `env` expects its ASGs to span in `region`, from
+/// the environment's `availability_zones` in the universe: a list, or lists
+/// keyed by region.
+pub fn expected_zones(u: &yaml_rust::Yaml, env: &str, region: &str) -> Vec<String> {
+    let z = &u["environments"][env]["availability_zones"];
+    let zones = if z.as_hash().is_some() { &z[region] } else { z };
+    zones.as_vec().cloned().unwrap_or_default().iter()
+        .filter_map(|z| z.as_str().map(|s| s.to_string()))
+        .collect()
+}
+
+/// Check that the subnets of `asg` cover every zone in `zones`, so rotations
+/// can keep instances in each of them.
+pub fn check_zones(r: rusoto_core::Region, asg: &AutoScaleGroup, zones: &[String]) -> Result<(), String> {
+    if zones.is_empty() {
+        return Ok(())
+    };
+
+    let covered = if asg.subnets.is_empty() {
+        asg.availability_zones.clone()
+    } else {
+        subnet_zones(r, &asg.subnets)
+    };
 
-    info!("will terminate these instances: {:?}", instances_to_terminate);
+    let missing: Vec<String> = zones.iter().filter(|z| !covered.contains(z)).cloned().collect();
+    if !missing.is_empty() {
+        return Err(format!("{} has no subnet in {} (its subnets are in {})", asg.name, missing.join(", "), covered.join(", ")))
+    };
+    Ok(())
+}
 
-	if (initial_desired + b) > initial_max {
-		let new_max_size = initial_desired + b;
-		let max_size_params = UpdateAutoScalingGroupType {
-			auto_scaling_group_name: n.clone(),
-			max_size:                Some(new_max_size as i64),
+fn subnet_zones(r: rusoto_core::Region, subnets: &[String]) -> Vec<String> {
+    let ec2_client = Ec2Client::new(r.to_owned());
+    let found = paginate(|token| {
+        let req = DescribeSubnetsRequest {
+            subnet_ids: Some(subnets.to_vec()),
+            next_token: token,
             ..Default::default()
-		};
+        };
+        match ec2_client.describe_subnets(req).sync() {
+            Ok(res) => (res.subnets.unwrap_or_default(), res.next_token),
+            Err(error) => panic!("ERROR: {:?}", error),
+        }
+    });
+    let mut zones: Vec<String> = found.into_iter().filter_map(|s| s.availability_zone).collect();
+    zones.sort();
+    zones.dedup();
+    zones
+}
 
-        match as_client.update_auto_scaling_group(max_size_params).sync() {
-            Ok(_k) => info!("updating max size to {}", new_max_size),
+/// Replace the InService instances of `n` that were not launched from its
+/// current launch configuration or launch template version, following
+/// `plan_rotation`.  Does nothing if every instance is up to date.  A
+/// suspension of the group that has run out is resumed first.
+/// `after_batch` is called with the batch number and the number of batches
+/// once each batch's old instances are gone; an `Err` from it stops the
+/// rotation.  Outdated instances still in the group after the last batch
+/// (say, because they were still launching when it started) are rotated in
+/// another pass.  The group is snapshotted first and restored from the
+/// snapshot however the rotation ends, which puts its size and processes
+/// back; a rotation only succeeds once the group is back at its desired
+/// capacity.  With `o.refresh` the group's native instance refresh does the
+/// replacing instead; see `refresh_instances`.
+pub fn rotate_instances<F>(r: rusoto_core::Region, n: String, o: &RotationOptions, mut after_batch: F) -> Result<(), String>
+    where F: FnMut(usize, usize) -> Result<(), String>
+{
+    let asg = resume_expired_suspension(r.clone(), &current_asg(r.clone(), &n));
+
+    if let Some(p) = asg.suspended_processes.iter().find(|p| REQUIRED_PROCESSES.contains(&p.as_str())) {
+        panic!("ERROR: {} has suspended process {}, which must be active to rotate instances (see `autoscalegroup resume --name {} --processes {}`)", n, p, n, p);
+    };
+    info!("verified {} has correct processes in place", n);
+
+    let target = launch_source(r.clone(), &asg);
+    let outdated = outdated_instances(&asg, &target);
+    let plan = rotation_plan(&asg, &outdated, o);
+    let up_to_date = format!("{} of {} InService instance(s) of {} already run {}",
+        in_service_instances(&asg).len() - outdated.len(), in_service_instances(&asg).len(), n, target);
+
+    if dry_run() {
+        println!("[dry-run] {}", up_to_date);
+        if !outdated.is_empty() {
+            for call in rotation_calls(&plan, o, &[]) {
+                println!("[dry-run] {}", call);
+            };
+        };
+        return Ok(())
+    };
+
+    info!("{}", up_to_date);
+    if outdated.is_empty() {
+        info!("{} is fully rotated; nothing to do", n);
+        return Ok(())
+    };
+    info!("will replace {} instance(s) of {} in {} batch(es): {:?}", outdated.len(), n, plan.batches.len(), outdated);
+
+    if o.refresh {
+        return refresh_instances(&r, &asg, &plan, &mut after_batch)
+    };
+
+    let before = keep_snapshot(&r, &asg);
+
+    // Processes someone else suspended stay suspended afterwards.
+    let processes: Vec<String> = ROTATION_PROCESSES.iter()
+        .filter(|p| !asg.suspended_processes.iter().any(|s| s == *p))
+        .map(|p| p.to_string())
+        .collect();
+    let as_client = AutoscalingClient::new(r.to_owned());
+    if !processes.is_empty() {
+        let process_req = ScalingProcessQuery {
+            auto_scaling_group_name: n.clone(),
+            scaling_processes: Some(processes.clone()),
+        };
+        match as_client.suspend_processes(process_req).sync() {
+            Ok(_k) => info!("temporarily suspended {} processes for {}", processes.join(" "), n),
             Err(error) => panic!("ERROR: {:?}", error),
         };
-	}
+    };
 
-	let new_desired_capacity = initial_desired + b;
-	let capacity_params = UpdateAutoScalingGroupType {
-		auto_scaling_group_name: n.clone(),
-		desired_capacity:        Some(new_desired_capacity as i64),
-        ..Default::default()
-	};
+    if plan.surge_max > plan.max {
+        set_capacity(&as_client, &n, Some(plan.surge_max), None);
+        info!("updating max size to {}", plan.surge_max);
+    };
 
-    match as_client.update_auto_scaling_group(capacity_params).sync() {
-        Ok(_k) => info!("resizing {} to new desired size: {}", n.clone(), new_desired_capacity),
-        Err(error) => panic!("ERROR: {:?}", error),
+    let result = run_rotation(&r, &as_client, plan.clone(), &target, o, &mut after_batch);
+
+    restore_snapshot(r.clone(), &before);
+
+    // Batches that took instances below the desired capacity leave the group
+    // short until the restored capacity is filled again.
+    let result = result.and_then(|_| {
+        info!("waiting for {} to be back at {} InService instance(s)...", n, plan.desired);
+        if wait_for_asg_in_service(r.clone(), &n, &plan.elbs, plan.desired as usize, BATCH_TIMEOUT) {
+            Ok(())
+        } else {
+            Err(format!("timed out waiting for {} to be back at {} InService instance(s) after the rotation", n, plan.desired))
+        }
+    });
+
+    if result.is_ok() {
+        info!("instance rotation is complete");
     };
+    result
+}
 
-	let elbs = asg[0].load_balancer_names.clone().unwrap();
+fn run_rotation<F>(r: &rusoto_core::Region, as_client: &AutoscalingClient, mut plan: RotationPlan, target: &str, o: &RotationOptions, after_batch: &mut F) -> Result<(), String>
+    where F: FnMut(usize, usize) -> Result<(), String>
+{
+    for pass in 1.. {
+        let total = plan.batches.len();
+        for (i, batch) in plan.batches.iter().enumerate() {
+            set_capacity(as_client, &plan.asg, None, Some(batch.desired));
+            info!("batch {} of {}: resized {} to desired size {}, waiting for new instances...", i + 1, total, plan.asg, batch.desired);
+
+            if !wait_for_asg_in_service(r.clone(), &plan.asg, &plan.elbs, batch.desired as usize, BATCH_TIMEOUT) {
+                return Err(format!("timed out waiting for {} instance(s) of {} to be InService in batch {} of {}", batch.desired, plan.asg, i + 1, total))
+            };
 
-	info!("starting to cull old instances");
+            let current = list_asg(r.clone(), plan.asg.clone()).into_iter().next().map(|a| a.instances).unwrap_or_default();
+            for id in &batch.terminate {
+                if !current.iter().any(|inst| &inst.instance_id == id) {
+                    info!("instance {} has already left {}", id, plan.asg);
+                    continue
+                };
+
+                let term_inst_params = TerminateInstanceInAutoScalingGroupType {
+                    instance_id: id.clone(),
+                    should_decrement_desired_capacity: true,
+                };
+                match as_client.terminate_instance_in_auto_scaling_group(term_inst_params).sync() {
+                    Ok(_k) => info!("instance {} has been terminated", id),
+                    Err(error) => panic!("ERROR: {:?}", error),
+                };
+            };
 
-    for e in elbs.iter().enumerate() {
-        let (mut i, item): (usize, &std::string::String) = e;
-        let mut _count = i += b;
-        let mut max = i + b;
-        if max > instances_to_terminate.len() {
-            max = instances_to_terminate.len();
+            after_batch(i + 1, total)?;
         };
 
-        if !wait_for_in_service(r.clone(), item.to_string(), new_desired_capacity, 60 * 15) {
-            panic!("ERROR: timed out waiting for instnaces to register with the ELB")
+        let mut asg = match list_asg(r.clone(), plan.asg.clone()).into_iter().next() {
+            Some(a) => a,
+            None => return Err(format!("{} disappeared during the rotation", plan.asg)),
+        };
+        let remaining = outdated_instances(&asg, target);
+        if remaining.is_empty() {
+            return Ok(())
+        };
+        if pass == MAX_PASSES {
+            return Err(format!("{} instance(s) of {} still do not run {} after {} passes: {}", remaining.len(), plan.asg, target, pass, remaining.join(", ")))
         };
 
-		for inst in instances_to_terminate[i..max].to_vec() {
-			info!("starting to remove instance: {}", inst.instance_id);
+        info!("{} outdated instance(s) are still in {}, rotating them too", remaining.len(), plan.asg);
+        // Batches that took instances below the desired capacity have left the
+        // group short of it, so the next pass is planned from the original.
+        asg.desired_capacity = plan.desired;
+        plan = plan_rotation(&asg, &remaining, o);
+    };
+    unreachable!()
+}
 
-            let term_inst_params = TerminateInstanceInAutoScalingGroupType {
-                instance_id: inst.instance_id.clone(),
-                should_decrement_desired_capacity: false,
-            };
+fn set_capacity(as_client: &AutoscalingClient, n: &str, max: Option<i64>, desired: Option<i64>) {
+    let params = UpdateAutoScalingGroupType {
+        auto_scaling_group_name: n.to_string(),
+        max_size:                max,
+        desired_capacity:        desired,
+        ..Default::default()
+    };
 
-            match as_client.terminate_instance_in_auto_scaling_group(term_inst_params).sync() {
-                Ok(_k) => info!("instance {} has been terminated", inst.instance_id.clone()),
-                Err(error) => panic!("ERROR: {:?}", error),
-            };
-		};
+    if let Err(error) = as_client.update_auto_scaling_group(params).sync() {
+        panic!("ERROR: {:?}", error);
     };
+}
 
-    info!("instance rotation is complete");
+/// Wait until `want` of the instances of `n` are InService on every ELB in
+/// `elbs`, or in the group itself if it has no ELBs.  Instances outside the
+/// group, such as a canary on the same ELB, are not counted.
+fn wait_for_asg_in_service(r: rusoto_core::Region, n: &str, elbs: &[String], want: usize, t: u64) -> bool {
+    let now = Instant::now();
 
-    let reset_params = UpdateAutoScalingGroupType {
-        auto_scaling_group_name: n.clone(),
-        max_size:                Some(initial_max as i64),
-        desired_capacity:        Some(initial_desired as i64),
-        ..Default::default()
+    while now.elapsed().as_secs() < t {
+        let asg = list_asg(r.clone(), n.to_string()).into_iter().next();
+        let members: Vec<String> = asg.as_ref().map(in_service_instances).unwrap_or_default();
+
+        let counts: Vec<usize> = if elbs.is_empty() {
+            vec![members.len()]
+        } else {
+            elbs.iter().map(|e| in_service_ids(r.clone(), e.clone()).iter().filter(|id| members.contains(id)).count()).collect()
+        };
+
+        info!("{}: want {} InService instances, have {:?}", n, want, counts);
+        if counts.iter().all(|&c| c >= want) {
+            return true
+        };
+
+        sleep(Duration::from_secs(15));
     };
 
-    match as_client.update_auto_scaling_group(reset_params).sync() {
-        Ok(_k) => info!("resized asg to previous size. max: {} desired: {}", initial_max, initial_desired),
-        Err(error) => panic!("ERROR: {:?}", error),
+    warn!("WARN: timeout while waiting for {} instances of {} to be InService", want, n);
+    false
+}
+
+/// IDs of the InService instances of `asg` that were not launched from
+/// `target`, in the form `launched_from` gives.
+pub fn outdated_instances(asg: &AutoScaleGroup, target: &str) -> Vec<String> {
+    asg.instances.iter()
+        .filter(|i| i.lifecycle_state == "InService" && launched_from(i) != target)
+        .map(|i| i.instance_id.clone())
+        .collect()
+}
+
+/// What an instance was launched from: its launch configuration name, or
+/// `TEMPLATE_ID:VERSION` if it came from a launch template.
+pub fn launched_from(i: &Instance) -> String {
+    match i.launch_template {
+        Some(ref t) => format!("{}:{}", t.launch_template_id.clone().unwrap_or_default(), t.version.clone().unwrap_or_default()),
+        None => i.launch_configuration_name.clone().unwrap_or_default(),
+    }
+}
+
+/// What new instances of `asg` are launched from, in the form
+/// `launched_from` gives.  A launch template's `$Latest` and `$Default`
+/// versions are looked up, since instances record the version number.
+pub fn launch_source(r: rusoto_core::Region, asg: &AutoScaleGroup) -> String {
+    let t = match asg.launch_template {
+        Some(ref t) => t,
+        None => return asg.lc_name.clone(),
     };
 
-    match as_client.resume_processes(process_req.clone()).sync() {
-        Ok(_k) => info!("resumed ReplaceUnhealthy AlarmNotification ScheduledActions AZRebalance processes for {}", n.clone()),
+    let ec2_client = Ec2Client::new(r.to_owned());
+    let req = DescribeLaunchTemplatesRequest {
+        launch_template_ids: t.launch_template_id.clone().map(|id| vec![id]),
+        launch_template_names: if t.launch_template_id.is_none() { t.launch_template_name.clone().map(|n| vec![n]) } else { None },
+        ..Default::default()
+    };
+    let template = match ec2_client.describe_launch_templates(req).sync() {
+        Ok(res) => res.launch_templates.unwrap_or_default().into_iter().next(),
         Err(error) => panic!("ERROR: {:?}", error),
     };
+    let template = match template {
+        Some(lt) => lt,
+        None => panic!("ERROR: the launch template of {} could not be found", asg.name),
+    };
+
+    let version = match t.version.as_deref().unwrap_or("$Default") {
+        "$Latest" => template.latest_version_number.unwrap_or_default().to_string(),
+        "$Default" => template.default_version_number.unwrap_or_default().to_string(),
+        v => v.to_string(),
+    };
+    format!("{}:{}", template.launch_template_id.unwrap_or_default(), version)
+}
+
+/// IDs of the instances in `asg` that are InService, in API order.
+pub fn in_service_instances(asg: &AutoScaleGroup) -> Vec<String> {
+    asg.instances.iter()
+        .filter(|i| i.lifecycle_state == "InService")
+        .map(|i| i.instance_id.clone())
+        .collect()
 }
 
-pub fn rotate_instances_cmd(r: rusoto_core::Region, m: &clap::ArgMatches) {
-    rotate_instances(r, m.value_of("name").unwrap().to_string(), m.value_of("batch").unwrap().parse::<usize>().unwrap());
+/// The plan `rotate_instances` follows to replace `outdated` instances of
+/// `asg`.  An instance refresh of a group with a launch configuration
+/// replaces every instance, so it is planned that way.
+pub fn rotation_plan(asg: &AutoScaleGroup, outdated: &[String], o: &RotationOptions) -> RotationPlan {
+    if o.refresh && asg.launch_template.is_none() && !outdated.is_empty() {
+        return plan_rotation(asg, &in_service_instances(asg), o)
+    };
+    plan_rotation(asg, outdated, o)
+}
+
+/// The ordered list of AWS calls (and waits) `rotate_instances` makes to
+/// carry out `plan`.  `after_batch` lists what runs after each batch.
+pub fn rotation_calls(plan: &RotationPlan, o: &RotationOptions, after_batch: &[String]) -> Vec<String> {
+    if o.refresh {
+        return refresh_calls(plan, after_batch)
+    };
+
+    let processes = ROTATION_PROCESSES.join(", ");
+    let mut calls = vec![aws_call("autoscaling", "SuspendProcesses", format!("{} ({})", plan.asg, processes))];
+
+    if plan.surge_max > plan.max {
+        calls.push(aws_call("autoscaling", "UpdateAutoScalingGroup", format!("{} (max: {})", plan.asg, plan.surge_max)));
+    };
+
+    let total = plan.batches.len();
+    for (i, batch) in plan.batches.iter().enumerate() {
+        calls.push(aws_call("autoscaling", "UpdateAutoScalingGroup", format!("{} (desired: {}, batch {} of {})", plan.asg, batch.desired, i + 1, total)));
+        if plan.elbs.is_empty() {
+            calls.push(format!("batch {}: wait for {} InService instances in {}", i + 1, batch.desired, plan.asg));
+        };
+        for e in &plan.elbs {
+            calls.push(format!("batch {}: wait for {} of {}'s instances to be InService on {}", i + 1, batch.desired, plan.asg, e));
+        };
+        for inst in &batch.terminate {
+            calls.push(aws_call("autoscaling", "TerminateInstanceInAutoScalingGroup", format!("{} (decrement desired, batch {})", inst, i + 1)));
+        };
+        calls.extend(after_batch.iter().map(|c| format!("batch {}: {}", i + 1, c)));
+    };
+
+    calls.push(aws_call("autoscaling", "UpdateAutoScalingGroup", format!("{} (max: {}, desired: {})", plan.asg, plan.max, plan.desired)));
+    calls.push(aws_call("autoscaling", "ResumeProcesses", format!("{} ({})", plan.asg, processes)));
+    if plan.elbs.is_empty() {
+        calls.push(format!("wait for {} InService instances in {}", plan.desired, plan.asg));
+    };
+    for e in &plan.elbs {
+        calls.push(format!("wait for {} of {}'s instances to be InService on {}", plan.desired, plan.asg, e));
+    };
+    calls
+}
+
+pub fn rotate_instances_cmd(r: rusoto_core::Region, m: &clap::ArgMatches, u: yaml_rust::Yaml) {
+    let name = m.value_of("name").unwrap().to_string();
+
+    if m.is_present("cancel") || m.is_present("rollback") {
+        let (action, op) = if m.is_present("rollback") { ("rollback refresh", "RollbackInstanceRefresh") } else { ("cancel refresh", "CancelInstanceRefresh") };
+        if skip_for_dry_run("autoscaling", op, name.clone()) {
+            return
+        };
+        audited(r.clone(), &u, asg_record(action, r.clone(), &name), |a| {
+            a.detail = name.clone();
+            if m.is_present("rollback") {
+                rollback_instance_refresh(&r, &name);
+            } else {
+                cancel_instance_refresh(&r, &name);
+            };
+        });
+        return
+    };
+
+    let method = m.value_of("method").unwrap();
+    if !ROTATION_METHODS.contains(&method) {
+        panic!("ERROR: unknown rotation method {} (available: {})", method, ROTATION_METHODS.join(", "));
+    };
+    let amount = |k: &str| m.value_of(k).map(|v| match Amount::parse(v) {
+        Ok(a) => a,
+        Err(e) => panic!("ERROR: --{}: {}", k, e),
+    });
+    let (max_surge, max_unavailable) = batch_sizes(
+        m.value_of("batch").unwrap_or("1").parse::<usize>().unwrap(),
+        amount("max-surge"),
+        amount("max-unavailable"),
+    );
+    let o = RotationOptions {
+        max_surge,
+        max_unavailable,
+        min_az_capacity: m.value_of("min-az-capacity").unwrap().parse::<f64>().unwrap() / 100.0,
+        refresh:         method == "refresh",
+    };
+
+    if let Some(asg) = list_asg(r.clone(), name.clone()).into_iter().next() {
+        if let Err(e) = check_zones(r.clone(), &asg, &expected_zones(&u, &asg.env_name, r.name())) {
+            panic!("ERROR: {}", e);
+        };
+    };
+
+    audited(r.clone(), &u, asg_record("rotate", r.clone(), &name), |a| {
+        a.detail = format!("{} max surge {}, max unavailable {}, min AZ capacity {}%, method {}",
+            name, o.max_surge, o.max_unavailable, o.min_az_capacity * 100.0, method);
+        if let Err(e) = rotate_instances(r.clone(), name.clone(), &o, |_, _| Ok(())) {
+            panic!("ERROR: {}", e);
+        };
+    });
 }
 
 pub fn updatelc_asg(r: rusoto_core::Region, n: String, lc: String) {
+    if skip_for_dry_run("autoscaling", "UpdateAutoScalingGroup", format!("{} (launch config: {})", n, lc)) {
+        return
+    };
+
     let as_client = AutoscalingClient::new(r.to_owned());
     let asg_req = UpdateAutoScalingGroupType {
         auto_scaling_group_name: n.clone(),
@@ -403,6 +1118,10 @@ pub fn updatelc_asg_cmd(r: rusoto_core::Region, m: &clap::ArgMatches) {
 }
 
 pub fn update_version_tag(r: rusoto_core::Region, n: String, v: String) {
+    if skip_for_dry_run("autoscaling", "CreateOrUpdateTags", format!("{} (version: {})", n, v)) {
+        return
+    };
+
     let as_client = AutoscalingClient::new(r.to_owned());
     let tag = Tag {
         key:                 "version".to_string(),
@@ -420,4 +1139,141 @@ pub fn update_version_tag(r: rusoto_core::Region, n: String, v: String) {
         Ok(_k) => info!("version tag successfully updated: {}", n.clone()),
         Err(error) => panic!("ERROR: {:?}", error),
     };
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A group of `desired` instances with max size `max`, with `n` InService
+    /// instances in each of `zones`, named after their zone.
+    fn group(zones: &[(&str, usize)], desired: i64, max: i64) -> AutoScaleGroup {
+        let instances: Vec<Instance> = zones.iter().flat_map(|&(z, n)| (0..n).map(move |i| Instance {
+            availability_zone: z.to_string(),
+            instance_id:       format!("{}-{}", z, i),
+            lifecycle_state:   "InService".to_string(),
+            health_status:     "Healthy".to_string(),
+            ..Default::default()
+        })).collect();
+
+        AutoScaleGroup {
+            name:                "asg".to_string(),
+            min_size:            0,
+            max_size:            max,
+            desired_capacity:    desired,
+            instance_count:      instances.len() as i64,
+            lc_name:             "lc".to_string(),
+            launch_template:     None,
+            version:             String::new(),
+            instances,
+            elbs:                Vec::new(),
+            suspended_processes: Vec::new(),
+            availability_zones:  zones.iter().map(|&(z, _)| z.to_string()).collect(),
+            subnets:             Vec::new(),
+            tags:                Vec::new(),
+            app_name:            String::new(),
+            env_name:            String::new(),
+        }
+    }
+
+    fn options(surge: Amount, unavailable: Amount, min_az_capacity: f64) -> RotationOptions {
+        RotationOptions { max_surge: surge, max_unavailable: unavailable, min_az_capacity, refresh: false }
+    }
+
+    fn ids(asg: &AutoScaleGroup) -> Vec<String> {
+        asg.instances.iter().map(|i| i.instance_id.clone()).collect()
+    }
+
+    fn terminated(plan: &RotationPlan) -> Vec<Vec<&str>> {
+        plan.batches.iter().map(|b| b.terminate.iter().map(|t| t.as_str()).collect()).collect()
+    }
+
+    #[test]
+    fn resolve_rounds_surge_up_and_unavailable_down() {
+        let o = options(Amount::Percent(25.0), Amount::Percent(25.0), 0.0);
+        assert_eq!(o.resolve(10), (3, 2));
+        assert_eq!(o.resolve(4), (1, 1));
+    }
+
+    #[test]
+    fn resolve_takes_counts_as_they_are() {
+        assert_eq!(options(Amount::Count(2), Amount::Count(1), 0.0).resolve(10), (2, 1));
+        assert_eq!(options(Amount::Count(0), Amount::Count(3), 0.0).resolve(10), (0, 3));
+    }
+
+    #[test]
+    fn resolve_always_replaces_one_instance() {
+        assert_eq!(options(Amount::Count(0), Amount::Count(0), 0.0).resolve(10), (1, 0));
+        assert_eq!(options(Amount::Percent(10.0), Amount::Percent(10.0), 0.0).resolve(0), (1, 0));
+    }
+
+    #[test]
+    fn batches_replace_surge_plus_unavailable() {
+        let asg = group(&[("a", 6)], 6, 6);
+        let plan = plan_rotation(&asg, &ids(&asg), &options(Amount::Count(2), Amount::Count(1), 0.0));
+
+        assert_eq!(terminated(&plan), vec![vec!["a-0", "a-1", "a-2"], vec!["a-3", "a-4", "a-5"]]);
+        assert!(plan.batches.iter().all(|b| b.desired == 8));
+        assert_eq!((plan.surge, plan.unavailable), (2, 1));
+    }
+
+    #[test]
+    fn a_short_last_batch_surges_less() {
+        let asg = group(&[("a", 5)], 5, 5);
+        let plan = plan_rotation(&asg, &ids(&asg), &options(Amount::Count(2), Amount::Count(0), 0.0));
+
+        assert_eq!(plan.batches.iter().map(|b| b.desired).collect::<Vec<_>>(), vec![7, 7, 6]);
+    }
+
+    #[test]
+    fn batches_take_from_each_zone_in_turn() {
+        let asg = group(&[("a", 2), ("b", 2)], 4, 4);
+        let plan = plan_rotation(&asg, &ids(&asg), &options(Amount::Count(2), Amount::Count(0), 0.0));
+
+        assert_eq!(terminated(&plan), vec![vec!["a-0", "b-0"], vec!["a-1", "b-1"]]);
+    }
+
+    #[test]
+    fn batches_keep_min_az_capacity_in_each_zone() {
+        let asg = group(&[("a", 4), ("b", 2)], 6, 6);
+        let plan = plan_rotation(&asg, &ids(&asg), &options(Amount::Count(6), Amount::Count(0), 0.5));
+
+        assert_eq!(terminated(&plan), vec![vec!["a-0", "b-0", "a-1"], vec!["b-1", "a-2", "a-3"]]);
+    }
+
+    #[test]
+    fn batches_may_always_take_one_instance_per_zone() {
+        let asg = group(&[("a", 1), ("b", 1)], 2, 2);
+        let plan = plan_rotation(&asg, &ids(&asg), &options(Amount::Count(2), Amount::Count(0), 0.9));
+
+        assert_eq!(terminated(&plan), vec![vec!["a-0", "b-0"]]);
+    }
+
+    #[test]
+    fn surge_max_makes_room_for_the_peak() {
+        let asg = group(&[("a", 4)], 4, 4);
+        let plan = plan_rotation(&asg, &ids(&asg), &options(Amount::Count(2), Amount::Count(0), 0.0));
+        assert_eq!((plan.peak_desired(), plan.surge_max, plan.max), (6, 6, 4));
+
+        let roomy = group(&[("a", 4)], 4, 10);
+        let plan = plan_rotation(&roomy, &ids(&roomy), &options(Amount::Count(2), Amount::Count(0), 0.0));
+        assert_eq!((plan.peak_desired(), plan.surge_max, plan.max), (6, 10, 10));
+    }
+
+    #[test]
+    fn a_single_instance_is_one_batch() {
+        let asg = group(&[("a", 1)], 1, 1);
+        let plan = plan_rotation(&asg, &ids(&asg), &options(Amount::Percent(25.0), Amount::Count(0), DEFAULT_MIN_AZ_CAPACITY));
+
+        assert_eq!(plan.batches, vec![RotationBatch { terminate: vec!["a-0".to_string()], desired: 2 }]);
+        assert_eq!(plan.surge_max, 2);
+    }
+
+    #[test]
+    fn no_instances_is_no_batches() {
+        let asg = group(&[("a", 3)], 3, 3);
+        let plan = plan_rotation(&asg, &[], &options(Amount::Count(1), Amount::Count(0), 0.0));
+
+        assert!(plan.batches.is_empty());
+        assert_eq!((plan.peak_desired(), plan.surge_max), (3, 3));
+    }
+}
@@ -0,0 +1,331 @@
+use std::panic::{self, AssertUnwindSafe};
+use std::thread;
+use prettytable::{Table, format};
+use yaml_rust::Yaml;
+use audit::panic_message;
+use deployment::{DeploymentContext, print_plan, resolve_deployment_with, run_deployment, undo_deployment};
+use manifest::{Settings, flatten_manifest};
+use regions::{environment_regions, regional_universe};
+use strategy::StrategyRegistry;
+use universe::load_yaml;
+use utils::dry_run;
+
+/// One application in a release bundle.
+#[derive(Debug, Clone, Default)]
+pub struct Member {
+    pub app:        std::string::String,
+    pub version:    std::string::String,
+    /// Applications in the bundle that must finish deploying first.
+    pub depends_on: Vec<std::string::String>,
+    /// Settings for this application only, as in a deployment manifest.
+    pub settings:   Settings,
+}
+
+/// Whether `deployment do` was asked to deploy a bundle rather than one
+/// application.
+pub fn is_bundle(m: &clap::ArgMatches) -> bool {
+    m.is_present("bundle") || m.is_present("release")
+}
+
+/// Read the members of the bundle from `--bundle` and `--release APP=VERSION`
+/// flags, which add members or override a member's version.  Returns the
+/// bundle's `env`, if it sets one.
+pub fn bundle_members(r: rusoto_core::Region, m: &clap::ArgMatches, errors: &mut Vec<String>) -> (Option<String>, Vec<Member>) {
+    let mut env = None;
+    let mut members: Vec<Member> = Vec::new();
+
+    if let Some(path) = m.value_of("bundle") {
+        let b = load_yaml(path.to_string(), r, "release bundle");
+        env = b["env"].as_str().map(|e| e.to_string());
+
+        match b["apps"].as_hash() {
+            Some(apps) => for (k, v) in apps {
+                let app = k.as_str().unwrap_or("").to_string();
+                members.push(bundle_member(&app, v, errors));
+            },
+            None => errors.push("release bundle must map apps to versions".to_string()),
+        };
+    };
+
+    for release in m.values_of("release").map(|v| v.collect::<Vec<&str>>()).unwrap_or_default() {
+        let (app, version) = match release.find('=') {
+            Some(i) => (&release[..i], &release[i + 1..]),
+            None => {
+                errors.push(format!("--release {} must be APP=VERSION", release));
+                continue
+            },
+        };
+        match members.iter_mut().find(|mb| mb.app == app) {
+            Some(mb) => mb.version = version.to_string(),
+            None => members.push(Member { app: app.to_string(), version: version.to_string(), ..Default::default() }),
+        };
+    };
+
+    errors.extend(member_errors(&members));
+    (env, members)
+}
+
+/// What is wrong with the members of a bundle: members without a version
+/// and dependencies on apps that are not in the bundle.
+fn member_errors(members: &[Member]) -> Vec<String> {
+    let mut errors = Vec::new();
+    for mb in members {
+        if mb.version.is_empty() {
+            errors.push(format!("{} has no version in the bundle", mb.app));
+        };
+        for d in &mb.depends_on {
+            if !members.iter().any(|o| &o.app == d) {
+                errors.push(format!("{} depends on {}, which is not in the bundle", mb.app, d));
+            };
+        };
+    };
+    errors
+}
+
+/// A bundle entry is either a version or a mapping with `version`,
+/// `depends_on` and any deployment manifest settings.
+fn bundle_member(app: &str, v: &Yaml, errors: &mut Vec<String>) -> Member {
+    let mut member = Member { app: app.to_string(), ..Default::default() };
+
+    match *v {
+        Yaml::String(ref s) => member.version = s.clone(),
+        Yaml::Integer(i) => member.version = i.to_string(),
+        Yaml::Hash(ref h) => {
+            let mut settings = h.clone();
+            for k in &["depends_on", "depends-on"] {
+                if let Some(d) = settings.remove(&Yaml::String(k.to_string())) {
+                    member.depends_on = match d {
+                        Yaml::String(s) => vec![s],
+                        Yaml::Array(a) => a.iter().filter_map(|x| x.as_str().map(|s| s.to_string())).collect(),
+                        _ => {
+                            errors.push(format!("{}.depends_on must be an app or a list of apps", app));
+                            Vec::new()
+                        },
+                    };
+                };
+            };
+
+            let mut local_errors = Vec::new();
+            member.settings = flatten_manifest(&Yaml::Hash(settings), &mut local_errors);
+            errors.extend(local_errors.iter().map(|e| format!("{}: {}", app, e)));
+            if let Some(v) = member.settings.remove("version") {
+                member.version = v.as_str().map(|s| s.to_string())
+                    .or_else(|| v.as_i64().map(|i| i.to_string()))
+                    .unwrap_or_default();
+            };
+        },
+        _ => errors.push(format!("{} must have a version or a mapping of settings", app)),
+    };
+
+    member
+}
+
+/// Group the members into layers: every member's dependencies are in
+/// earlier layers, and the members of a layer can be deployed in parallel.
+pub fn bundle_layers(members: &[Member]) -> Result<Vec<Vec<usize>>, String> {
+    let mut done = vec![false; members.len()];
+    let mut layers = Vec::new();
+
+    while done.iter().any(|d| !d) {
+        let ready = |i: usize| members[i].depends_on.iter()
+            .all(|d| members.iter().position(|o| &o.app == d).map(|j| done[j]).unwrap_or(true));
+        let layer: Vec<usize> = (0..members.len()).filter(|&i| !done[i] && ready(i)).collect();
+
+        if layer.is_empty() {
+            let stuck: Vec<String> = (0..members.len()).filter(|&i| !done[i]).map(|i| members[i].app.clone()).collect();
+            return Err(format!("the dependencies of {} form a cycle", stuck.join(", ")))
+        };
+        for &i in &layer {
+            done[i] = true;
+        };
+        layers.push(layer);
+    };
+
+    Ok(layers)
+}
+
+/// Resolve every member of the bundle before anything is changed, so a
+/// mistake in any member stops the bundle before it starts.  A bundle
+/// deploys to one region; an environment with several is refused.
+pub fn resolve_bundle(r: rusoto_core::Region, m: &clap::ArgMatches, u: &Yaml, s: &StrategyRegistry) -> (Vec<Member>, Vec<Vec<usize>>, Vec<DeploymentContext>) {
+    let mut errors = Vec::new();
+    let (bundle_env, members) = bundle_members(r.clone(), m, &mut errors);
+
+    let env = m.value_of("env").map(|e| e.to_string()).or(bundle_env);
+    if env.is_none() {
+        errors.push("env is required for a bundle (--env or env in the bundle)".to_string());
+    };
+    if members.is_empty() {
+        errors.push("the bundle has no apps".to_string());
+    };
+    let regions = env.as_ref().map(|e| environment_regions(u, e)).unwrap_or_default();
+    if regions.len() > 1 {
+        errors.push(format!("{} deploys to {}, but a bundle can only deploy to one region; deploy each app on its own with `deployment do --app`",
+            env.clone().unwrap(), regions.iter().map(|r| r.name()).collect::<Vec<_>>().join(", ")));
+    };
+    let r = regions.first().cloned().unwrap_or(r);
+
+    let layers = match bundle_layers(&members) {
+        Ok(l) => l,
+        Err(e) => {
+            errors.push(e);
+            Vec::new()
+        },
+    };
+
+    if !errors.is_empty() {
+        panic!("ERROR: invalid release bundle:\n  - {}", errors.join("\n  - "));
+    };
+
+    let ctxs = members.iter().map(|mb| {
+        let mut overrides = mb.settings.clone();
+        overrides.insert("app".to_string(), Yaml::String(mb.app.clone()));
+        overrides.insert("version".to_string(), Yaml::String(mb.version.clone()));
+        overrides.insert("env".to_string(), Yaml::String(env.clone().unwrap()));
+        resolve_deployment_with(r.clone(), m, &regional_universe(u, &mb.app, r.name()), s, overrides)
+    }).collect();
+
+    (members, layers, ctxs)
+}
+
+pub fn plan_bundle(r: rusoto_core::Region, m: &clap::ArgMatches, u: Yaml, s: &StrategyRegistry) {
+    let (_members, layers, ctxs) = resolve_bundle(r, m, &u, s);
+    print_bundle_plan(&layers, &ctxs, s);
+}
+
+fn print_bundle_plan(layers: &[Vec<usize>], ctxs: &[DeploymentContext], s: &StrategyRegistry) {
+    for (n, layer) in layers.iter().enumerate() {
+        let apps: Vec<String> = layer.iter().map(|&i| format!("{} {}", ctxs[i].deploy.application, ctxs[i].deploy.version)).collect();
+        println!("=== Layer {} of {} (in parallel): {}", n + 1, layers.len(), apps.join(", "));
+        println!();
+        for &i in layer {
+            print_plan(&ctxs[i], s.get(&ctxs[i].deploy.strategy).unwrap());
+            println!();
+        };
+    };
+    println!("If any member fails, every member already deployed is rolled back.");
+}
+
+/// Deploy every member of the bundle, a layer at a time with the members of
+/// each layer in parallel.  If any member fails, no further layers start and
+/// every member that was deployed is rolled back, newest first.
+pub fn deploy_bundle(r: rusoto_core::Region, m: &clap::ArgMatches, u: Yaml, s: &StrategyRegistry) {
+    let (members, layers, ctxs) = resolve_bundle(r, m, &u, s);
+    if dry_run() {
+        print_bundle_plan(&layers, &ctxs, s);
+        return
+    };
+
+    let mut results: Vec<String> = vec!["not started".to_string(); members.len()];
+    let mut pending: Vec<Option<DeploymentContext>> = ctxs.into_iter().map(Some).collect();
+    let mut deployed: Vec<(usize, DeploymentContext)> = Vec::new();
+    let mut failed = false;
+
+    for (n, layer) in layers.iter().enumerate() {
+        if failed {
+            break
+        };
+        info!("deploying layer {} of {}: {}", n + 1, layers.len(),
+            layer.iter().map(|&i| members[i].app.clone()).collect::<Vec<_>>().join(", "));
+
+        let u = &u;
+        let outcomes: Vec<(usize, Result<DeploymentContext, String>)> = thread::scope(|scope| {
+            let handles: Vec<_> = layer.iter().map(|&i| {
+                let ctx = pending[i].take().unwrap();
+                (i, scope.spawn(move || {
+                    run_deployment(&ctx, u, s.get(&ctx.deploy.strategy).unwrap());
+                    ctx
+                }))
+            }).collect();
+            handles.into_iter().map(|(i, h)| (i, h.join().map_err(|e| panic_message(&*e)))).collect()
+        });
+
+        for (i, outcome) in outcomes {
+            match outcome {
+                Ok(ctx) => {
+                    results[i] = "deployed".to_string();
+                    deployed.push((i, ctx));
+                },
+                Err(e) => {
+                    results[i] = format!("failed and rolled back: {}", e);
+                    failed = true;
+                },
+            };
+        };
+    };
+
+    if failed {
+        for (i, ctx) in deployed.iter().rev() {
+            let rolled_back = panic::catch_unwind(AssertUnwindSafe(|| {
+                undo_deployment(ctx, &u, s.get(&ctx.deploy.strategy).unwrap(), "another member of the bundle failed");
+            }));
+            results[*i] = match rolled_back {
+                Ok(_) => "deployed, then rolled back".to_string(),
+                Err(e) => format!("deployed, then rollback failed: {}", panic_message(&*e)),
+            };
+        };
+    };
+
+    let mut table = Table::new();
+    table.set_format(*format::consts::FORMAT_NO_LINESEP_WITH_TITLE);
+    table.set_titles(row!["Application", "Version", "Result"]);
+    for (i, mb) in members.iter().enumerate() {
+        table.add_row(row![mb.app, mb.version, results[i]]);
+    };
+    table.printstd();
+
+    if failed {
+        panic!("ERROR: bundle deployment failed; every deployed member was rolled back");
+    };
+    info!("bundle deployment is complete");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn member(app: &str, depends_on: &[&str]) -> Member {
+        Member {
+            app:        app.to_string(),
+            version:    "r1".to_string(),
+            depends_on: depends_on.iter().map(|d| d.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn independent_members_share_one_layer() {
+        let members = vec![member("api", &[]), member("worker", &[]), member("cron", &[])];
+        assert_eq!(bundle_layers(&members), Ok(vec![vec![0, 1, 2]]));
+    }
+
+    #[test]
+    fn a_chain_of_dependents_takes_a_layer_each() {
+        let members = vec![member("web", &["api"]), member("api", &["db-migrate"]), member("db-migrate", &[]), member("cron", &["db-migrate"])];
+        assert_eq!(bundle_layers(&members), Ok(vec![vec![2], vec![1, 3], vec![0]]));
+    }
+
+    #[test]
+    fn a_cycle_is_refused() {
+        let members = vec![member("cron", &[]), member("api", &["worker"]), member("worker", &["api"])];
+        assert_eq!(bundle_layers(&members), Err("the dependencies of api, worker form a cycle".to_string()));
+    }
+
+    #[test]
+    fn an_unknown_dependency_does_not_hold_a_member_back() {
+        // member_errors reports it; the layers are still worked out so
+        // every error is reported at once.
+        let members = vec![member("api", &["billing"]), member("web", &["api"])];
+        assert_eq!(bundle_layers(&members), Ok(vec![vec![0], vec![1]]));
+    }
+
+    #[test]
+    fn an_unknown_dependency_is_an_error() {
+        let mut members = vec![member("api", &["billing"]), member("web", &["api"])];
+        members[1].version = String::new();
+        assert_eq!(member_errors(&members), vec![
+            "api depends on billing, which is not in the bundle".to_string(),
+            "web has no version in the bundle".to_string(),
+        ]);
+    }
+}
@@ -1,149 +1,841 @@
-use std::{thread, time};
-use launchconfig::create_lc;
-use loadbalancer::{elb_stats, in_service, wait_for_in_service};
-use autoscalegroup::{list_asg, resize_asg, rotate_instances, updatelc_asg, update_version_tag};
+use std::{cmp, thread, time};
+use std::cell::RefCell;
+use prettytable::{Table, format};
+use rusoto_autoscaling::{CreateLaunchConfigurationType, LaunchConfiguration};
+use launchconfig::{create_launch_config, lc_history, lc_name, lc_version, security_groups};
+use manifest::{Settings, build_deployment, default_hooks, deployment_settings};
+use hooks::{Hooks, hook_calls, run_hooks};
+use processes::{resume_expired_calls, resume_expired_suspension};
+use notify::{Notifier, notifier, notify};
+use newrelic::{Marker, api_url, app_id, mark_deployment};
+use probe::{Probe, probe_calls, probe_config, probe_new_instances};
+use alarms::{alarm_selectors, check_alarms, check_alarms_calls};
+use version::{DEFAULT_SCHEME, check_version};
+use loadbalancer::{elb_stats, in_service, in_service_ids, wait_for_in_service};
+use autoscalegroup::{Amount, AutoScaleGroup, DEFAULT_MIN_AZ_CAPACITY, RotationOptions, check_zones, expected_zones,
+    attach_elb, detach_elb, outdated_instances, list_asg, resize_asg, rotate_instances, rotation_calls, rotation_plan,
+    updatelc_asg, update_version_tag};
+use strategy::{DeploymentStrategy, Rolling, StrategyRegistry};
+use snapshot::{AsgSnapshot, keep_snapshot, restore_snapshot};
+use utils::{aws_call, dry_run};
+use bundle::{deploy_bundle, is_bundle, plan_bundle};
+use regions::{deploy_regions, plan_regions, regional_universe};
+use audit::{AuditRecord, audited, new_record};
 
 #[derive(Debug, Clone)]
 pub struct Deployment {
-	application:            std::string::String,
-	environment:            std::string::String,
-	iam_profile:            std::string::String,
-	instance_type:          std::string::String,
-	version:                std::string::String,
-	force:                  bool,
-	max_latency:            f64,
-	max_error_rate:         f64,
-	user_data:              std::string::String,
-	healthcheck_timeout:    u64,
-    strategy:               std::string::String,
-	batch:                  usize,
+	pub application:            std::string::String,
+	pub environment:            std::string::String,
+	pub iam_profile:            std::string::String,
+	pub instance_type:          std::string::String,
+	pub version:                std::string::String,
+	pub ami:                    std::string::String,
+	pub force:                  bool,
+	pub max_latency:            f64,
+	pub max_error_rate:         f64,
+	pub user_data:              std::string::String,
+	pub healthcheck_timeout:    u64,
+	pub strategy:               std::string::String,
+	/// Instances, or a percentage of desired capacity, a rolling batch may add.
+	pub max_surge:              Amount,
+	/// Instances, or a percentage of desired capacity, a rolling batch may remove.
+	pub max_unavailable:        Amount,
+	/// Fraction of each availability zone's instances kept while a batch is replaced.
+	pub min_az_capacity:        f64,
+	/// How instances are replaced: `manual` batches or an instance `refresh`.
+	pub rotation_method:        std::string::String,
+	pub hooks:                  Hooks,
+	pub version_scheme:         std::string::String,
+	pub allow_downgrade:        bool,
+	pub redeploy:               bool,
+	/// Where to wait for approval after the canary; see `approval::await_approval`.
+	pub approval:               Option<std::string::String>,
+	pub approval_timeout:       u64,
+}
+
+/// Everything a `DeploymentStrategy` needs to know about the deployment in
+/// progress, resolved once before any strategy phase runs.
+#[derive(Debug, Clone)]
+pub struct DeploymentContext {
+    pub region:          rusoto_core::Region,
+    pub deploy:          Deployment,
+    pub elb:             std::string::String,
+    pub lc_name:         std::string::String,
+    pub security_groups: Vec<std::string::String>,
+    pub blue_asg:        std::string::String,
+    pub green_asg:       std::string::String,
+    pub blue_asg_info:   AutoScaleGroup,
+    pub green_asg_info:  AutoScaleGroup,
+    /// The last ELB stats `judge_canary` judged, kept for the audit trail.
+    pub canary_stats:    RefCell<Vec<f64>>,
+    pub notifier:        Notifier,
+    pub probe:           Probe,
+    /// CloudWatch alarm names (or `prefix*`) that must stay out of ALARM.
+    pub alarms:          Vec<std::string::String>,
 }
 
 pub fn do_deployment_cmd(r: rusoto_core::Region, m: &clap::ArgMatches, u: yaml_rust::Yaml) {
+    if is_bundle(m) {
+        deploy_bundle(r, m, u, &StrategyRegistry::default());
+        return
+    };
+    do_deployment(r, m, u, &StrategyRegistry::default());
+}
+
+pub fn plan_deployment_cmd(r: rusoto_core::Region, m: &clap::ArgMatches, u: yaml_rust::Yaml) {
+    if is_bundle(m) {
+        plan_bundle(r, m, u, &StrategyRegistry::default());
+        return
+    };
+    plan_deployment(r, m, u, &StrategyRegistry::default());
+}
+
+/// Resolve the universe, AMI, launch configuration name and ASGs for a
+/// deployment from the command line and any `--manifest`.  Only reads from
+/// AWS.
+pub fn resolve_deployment(r: rusoto_core::Region, m: &clap::ArgMatches, u: &yaml_rust::Yaml, s: &StrategyRegistry) -> DeploymentContext {
+    resolve_deployment_with(r, m, u, s, Settings::new())
+}
+
+/// `resolve_deployment` with `overrides` taking precedence over every other
+/// source of settings.
+pub fn resolve_deployment_with(r: rusoto_core::Region, m: &clap::ArgMatches, u: &yaml_rust::Yaml, s: &StrategyRegistry, overrides: Settings) -> DeploymentContext {
+    let (settings, errors) = deployment_settings(r.clone(), m, u, overrides);
+    let deploy = build_deployment(r.clone(), &settings, errors, u, s);
+
+    let elb = u["applications"][deploy.application.as_str()]["elb"][deploy.environment.as_str()].as_str().unwrap().to_string();
+    let blue_asg = format!("{}-{}-blue", deploy.application, deploy.environment);
+    let green_asg = format!("{}-{}-green", deploy.application, deploy.environment);
+
+    let bsg = list_asg(r.clone(), blue_asg.clone());
+    let gsg = list_asg(r.clone(), green_asg.clone());
+    if bsg.is_empty() || gsg.is_empty() {
+        panic!("there is a problem getting the auto scaling group information");
+    };
+
+    if let Err(e) = check_version(&deploy, &gsg[0].version) {
+        panic!("ERROR: {}", e);
+    };
+    let zones = expected_zones(u, &deploy.environment, r.name());
+    for asg in &[&bsg[0], &gsg[0]] {
+        if let Err(e) = check_zones(r.clone(), asg, &zones) {
+            panic!("ERROR: {}", e);
+        };
+    };
+
+    DeploymentContext {
+        region:          r,
+        lc_name:         lc_name(&deploy.application, &deploy.environment, &deploy.version),
+        security_groups: security_groups(u, &deploy.application, &deploy.environment),
+        notifier:        notifier(u, &deploy.application),
+        probe:           probe_config(u, &deploy.application),
+        alarms:          alarm_selectors(u, &deploy.application, &deploy.environment),
+        deploy,
+        elb,
+        blue_asg,
+        green_asg,
+        blue_asg_info:   bsg[0].clone(),
+        green_asg_info:  gsg[0].clone(),
+        canary_stats:    RefCell::new(Vec::new()),
+    }
+}
+
+pub fn plan_deployment(r: rusoto_core::Region, m: &clap::ArgMatches, u: yaml_rust::Yaml, s: &StrategyRegistry) {
+    plan_regions(r, m, &u, s, Settings::new());
+}
+
+pub fn print_plan(c: &DeploymentContext, strategy: &dyn DeploymentStrategy) {
+    print_plan_summary(c, strategy);
+
+    let mut calls = check_alarms_calls(c, "before the deployment");
+    calls.extend(hook_calls(c, "before_launch_config"));
+    calls.push(create_lc_call(c));
+    calls.extend(resume_expired_calls(&c.green_asg_info));
+    calls.extend(resume_expired_calls(&c.blue_asg_info));
+    calls.extend(strategy.plan(c));
+    print_plan_calls(&calls);
+}
+
+fn print_plan_summary(c: &DeploymentContext, strategy: &dyn DeploymentStrategy) {
+    let (blue_peak, green_peak) = strategy.peak_capacity(c);
+
+    println!("Deployment plan for {} {} in {} (strategy: {})", c.deploy.application, c.deploy.version, c.deploy.environment, strategy.name());
+    println!();
+    println!("  Version:              {}", match check_version(&c.deploy, &c.green_asg_info.version) { Ok(v) | Err(v) => v });
+    println!("  ELB:                  {}", c.elb);
+    println!("  AMI:                  {}", c.deploy.ami);
+    println!("  Launch configuration: {} ({}, {})", c.lc_name, c.deploy.instance_type, c.deploy.iam_profile);
+    println!("  Security groups:      {}", c.security_groups.join(", "));
+    if c.deploy.force {
+        println!("  Thresholds:           none (force deploy)");
+    } else {
+        println!("  Thresholds:           error rate <= {:.2}%, latency <= {:.0} ms, healthy within {} min",
+            c.deploy.max_error_rate * 100.0, c.deploy.max_latency * 1000.0, c.deploy.healthcheck_timeout);
+    };
+    println!();
+
+    let mut table = Table::new();
+    table.set_format(*format::consts::FORMAT_NO_LINESEP_WITH_TITLE);
+    table.set_titles(row!["ASG Name", "Launch Config (Current)", "Version (Current)", "Min/Max/Desired (Current)", "Peak Desired", "Desired (After)"]);
+    for &(asg, peak, after) in &[(&c.blue_asg_info, blue_peak, 0), (&c.green_asg_info, green_peak, c.green_asg_info.desired_capacity)] {
+        table.add_row(row![
+            asg.name,
+            asg.lc_name,
+            asg.version,
+            format!("{}/{}/{}", asg.min_size, asg.max_size, asg.desired_capacity),
+            peak,
+            after
+        ]);
+    };
+    table.printstd();
+    println!();
+
+    let outdated = outdated_instances(&c.green_asg_info, &c.lc_name);
+    let plan = rotation_plan(&c.green_asg_info, &outdated, &rolling_rotation(c));
+    println!("Batch schedule for {} (max surge {} = {}, max unavailable {} = {}, {} instance(s) not yet on {}):",
+        c.green_asg, c.deploy.max_surge, plan.surge, c.deploy.max_unavailable, plan.unavailable, outdated.len(), c.lc_name);
+    for (i, batch) in plan.batches.iter().enumerate() {
+        println!("  batch {}: {} (desired {})", i + 1, batch.terminate.join(", "), batch.desired);
+    };
+    println!();
+}
+
+fn print_plan_calls(calls: &[String]) {
+    println!("AWS calls:");
+    for (i, call) in calls.iter().enumerate() {
+        println!("  {:>3}. {}", i + 1, call);
+    };
+}
+
+pub fn do_deployment(r: rusoto_core::Region, m: &clap::ArgMatches, u: yaml_rust::Yaml, s: &StrategyRegistry) {
+    deploy_regions(r, m, &u, s, Settings::new());
+}
+
+/// Carry out a resolved deployment, or print its plan in dry runs.  Panics
+/// if the deployment fails, after rolling it back.
+pub fn run_deployment(ctx: &DeploymentContext, u: &yaml_rust::Yaml, strategy: &dyn DeploymentStrategy) {
+    if dry_run() {
+        print_plan(ctx, strategy);
+        return
+    };
+
+    audited(ctx.region.clone(), u, deployment_record("deploy", ctx), |a| {
+        if let Err(e) = check_alarms(ctx, "before the deployment") {
+            deployment_failed(ctx, &e);
+            panic!("ERROR: deployment refused: {}", e);
+        };
+
+        info!("deploying {} to {} using the {} strategy",
+            check_version(&ctx.deploy, &ctx.green_asg_info.version).unwrap_or_default(), ctx.deploy.environment, ctx.deploy.strategy);
+        notify(ctx, "started", &format!("deploying from {} using the {} strategy", ctx.green_asg_info.version, ctx.deploy.strategy));
+
+        if let Err(e) = run_hooks(ctx, "before_launch_config", None) {
+            deployment_failed(ctx, &e);
+            panic!("ERROR: deployment aborted: {}", e);
+        };
+
+        let created = create_launch_config(ctx.region.clone(), CreateLaunchConfigurationType {
+            launch_configuration_name: ctx.lc_name.clone(),
+            image_id: Some(ctx.deploy.ami.clone()),
+            instance_type: Some(ctx.deploy.instance_type.clone()),
+            iam_instance_profile: Some(ctx.deploy.iam_profile.clone()),
+            user_data: Some(ctx.deploy.user_data.clone()),
+            security_groups: Some(ctx.security_groups.clone()),
+            ..Default::default()
+        });
+        if let Err(e) = created {
+            deployment_failed(ctx, &e);
+            panic!("ERROR: deployment aborted: {}", e);
+        };
+
+        for asg in &[&ctx.green_asg_info, &ctx.blue_asg_info] {
+            keep_snapshot(&ctx.region, &resume_expired_suspension(ctx.region.clone(), asg));
+        };
+
+        let result = strategy.execute(ctx);
+        a.canary_stats = ctx.canary_stats.borrow().clone();
+        if let Err(e) = result {
+            warn!("WARN: deployment failed, rolling back: {}", e);
+            deployment_failed(ctx, &e);
+            strategy.rollback(ctx);
+            notify(ctx, "rolled_back", &format!("rolled back to {}", ctx.green_asg_info.version));
+            let _ = run_hooks(ctx, "on_rollback", Some(&e));
+            panic!("ERROR: {}", e);
+        };
+
+        info!("deployment of {} to {} is complete", ctx.deploy.version, ctx.deploy.environment);
+        notify(ctx, "completed", "deployment complete");
+        mark_new_relic(u, ctx, &a.user);
+    });
+}
+
+/// Send the `failed` notification and run the `on_failure` hook, however
+/// the deployment failed.
+fn deployment_failed(c: &DeploymentContext, e: &str) {
+    notify(c, "failed", e);
+    let _ = run_hooks(c, "on_failure", Some(e));
+}
+
+/// Mark a finished deployment in New Relic if the application has an ID for
+/// this environment and `NEW_RELIC_API_KEY` is set.  A failure is only logged.
+fn mark_new_relic(u: &yaml_rust::Yaml, c: &DeploymentContext, user: &str) {
+    let id = match app_id(u, &c.deploy.application, &c.deploy.environment) {
+        Some(id) => id,
+        None => return,
+    };
+    let key = match std::env::var("NEW_RELIC_API_KEY") {
+        Ok(k) => k,
+        Err(_) => {
+            info!("NEW_RELIC_API_KEY is not set, not marking the deployment in New Relic");
+            return
+        },
+    };
+
+    let marker = Marker {
+        app_id:      id,
+        revision:    c.deploy.version.clone(),
+        description: format!("burnish {} deployment to {}", c.deploy.strategy, c.deploy.environment),
+        user:        user.to_string(),
+        changelog:   format!("{} -> {} ({})", c.green_asg_info.version, c.deploy.version, c.lc_name),
+    };
+    if let Err(e) = mark_deployment(&api_url(u), &key, &marker) {
+        warn!("WARN: could not mark the deployment in New Relic: {}", e);
+    };
+}
+
+pub fn mark_deployment_cmd(_r: rusoto_core::Region, m: &clap::ArgMatches, u: yaml_rust::Yaml) {
+    let app_name = m.value_of("app").unwrap();
+    let env_name = m.value_of("env").unwrap();
+
+    let id = match app_id(&u, app_name, env_name) {
+        Some(id) => id,
+        None => panic!("ERROR: {} has no new_relic_app_id for {} in this universe.", app_name, env_name),
+    };
+    let key = match m.value_of("api-key").map(|k| k.to_string()).or_else(|| std::env::var("NEW_RELIC_API_KEY").ok()) {
+        Some(k) => k,
+        None => panic!("ERROR: a New Relic API key is required (--api-key or NEW_RELIC_API_KEY)"),
+    };
+    let url = match m.value_of("api-url") {
+        Some(url) => url.trim_end_matches('/').to_string(),
+        None => api_url(&u),
+    };
+
+    let marker = Marker {
+        app_id:      id,
+        revision:    m.value_of("version").unwrap().to_string(),
+        description: m.value_of("description").unwrap_or("").to_string(),
+        user:        m.value_of("user").map(|u| u.to_string()).unwrap_or_else(|| new_record("mark").user),
+        changelog:   String::new(),
+    };
+    if let Err(e) = mark_deployment(&url, &key, &marker) {
+        panic!("ERROR: {}", e);
+    };
+}
+
+/// Roll back a deployment that completed, e.g. because another member of
+/// its bundle failed.  The green ASG returns to the launch configuration and
+/// version it had before.
+pub fn undo_deployment(ctx: &DeploymentContext, u: &yaml_rust::Yaml, strategy: &dyn DeploymentStrategy, reason: &str) {
+    if dry_run() {
+        return
+    };
+
+    let mut record = deployment_record("rollback", ctx);
+    record.version = ctx.green_asg_info.version.clone();
+    record.previous_version = ctx.deploy.version.clone();
+    record.launch_config = ctx.green_asg_info.lc_name.clone();
+
+    audited(ctx.region.clone(), u, record, |_a| {
+        warn!("WARN: rolling {} in {} back to {}: {}", ctx.deploy.application, ctx.deploy.environment, ctx.green_asg_info.version, reason);
+        strategy.rollback(ctx);
+        notify(ctx, "rolled_back", &format!("rolled back to {}: {}", ctx.green_asg_info.version, reason));
+        let _ = run_hooks(ctx, "on_rollback", Some(reason));
+    });
+}
+
+/// Start an audit record describing the deployment in `c`.
+fn deployment_record(action: &str, c: &DeploymentContext) -> AuditRecord {
+    let mut record = new_record(action);
+    record.app = c.deploy.application.clone();
+    record.env = c.deploy.environment.clone();
+    record.version = c.deploy.version.clone();
+    record.previous_version = c.green_asg_info.version.clone();
+    record.launch_config = c.lc_name.clone();
+    record.strategy = c.deploy.strategy.clone();
+    record.detail = format!("region {}, max surge {}, max unavailable {}, force {}", c.region.name(), c.deploy.max_surge, c.deploy.max_unavailable, c.deploy.force);
+    record
+}
+
+pub fn rollback_deployment_cmd(r: rusoto_core::Region, m: &clap::ArgMatches, u: yaml_rust::Yaml) {
     if u["applications"][m.value_of("app").unwrap()].is_badvalue() {
-        panic!("Application {} does not exist in this universe.", m.value_of("app").unwrap().to_string());
+        panic!("Application {} does not exist in this universe.", m.value_of("app").unwrap());
     };
 
     if u["environments"][m.value_of("env").unwrap()].is_badvalue() {
-        panic!("Environment {} does not exist in this universe.", m.value_of("env").unwrap().to_string());
+        panic!("Environment {} does not exist in this universe.", m.value_of("env").unwrap());
     };
 
-    if m.value_of("version").unwrap().to_string().chars().count() > 255 {
-        panic!("max length for version string is 255 chars.");
+    let app_name = m.value_of("app").unwrap();
+    let env_name = m.value_of("env").unwrap();
+    let u = regional_universe(&u, app_name, r.name());
+    let blue_asg = format!("{}-{}-blue", app_name, env_name);
+    let green_asg = format!("{}-{}-green", app_name, env_name);
+
+    let bsg = list_asg(r.clone(), blue_asg.clone());
+    let gsg = list_asg(r.clone(), green_asg.clone());
+    if bsg.is_empty() || gsg.is_empty() {
+        panic!("there is a problem getting the auto scaling group information");
+    };
+
+    let history = lc_history(r.clone(), &u, app_name, env_name);
+    let target = match previous_lc(&history, app_name, env_name, &gsg[0], m.value_of("to")) {
+        Some(lc) => lc,
+        None => panic!("ERROR: could not find a launch configuration to roll {} back to", green_asg),
+    };
+
+    let canary = m.is_present("canary");
+    let batch = match m.value_of("batch") {
+        Some(b) => b.parse::<usize>().unwrap(),
+        None => cmp::max(1, (gsg[0].instance_count as usize).div_ceil(2)),
     };
 
     let deploy = Deployment {
-        application:            m.value_of("app").unwrap().to_string(),
-        environment:            m.value_of("env").unwrap().to_string(),
-        iam_profile:            m.value_of("iam-profile").unwrap().to_string(),
-        instance_type:          m.value_of("instance-type").unwrap().to_string(),
-        version:                m.value_of("version").unwrap().to_string(),
-        force:                  m.value_of("force").unwrap().parse::<bool>().unwrap(),
+        application:            app_name.to_string(),
+        environment:            env_name.to_string(),
+        iam_profile:            target.iam_instance_profile.clone().unwrap_or_default(),
+        instance_type:          target.instance_type.clone(),
+        version:                lc_version(&target.launch_configuration_name, app_name, env_name).unwrap(),
+        ami:                    target.image_id.clone(),
+        force:                  !canary,
         max_latency:            m.value_of("max-latency").unwrap().parse::<f64>().unwrap() / 1000.0,
         max_error_rate:         m.value_of("max-error-rate").unwrap().parse::<f64>().unwrap() / 100.0,
-        user_data:              m.value_of("user-data").unwrap().to_string(),
+        user_data:              target.user_data.clone().unwrap_or_default(),
         healthcheck_timeout:    m.value_of("timeout").unwrap().parse::<u64>().unwrap(),
-        strategy:               m.value_of("strategy").unwrap().to_string(),
-        batch:                  m.value_of("batch").unwrap().parse::<usize>().unwrap(),
-    };
-
-    let app = &u["applications"][m.value_of("app").unwrap()];
-    let lc = create_lc(r.clone(), m, u.clone());
-    let elb = app["elb"][m.value_of("env").unwrap()].as_str().unwrap().to_string();
-    let blue_asg = format!(
-        "{}-{}-blue",
-        m.value_of("app").unwrap().to_string(),
-        m.value_of("env").unwrap().to_string(),
-    );
-    let green_asg = format!(
-        "{}-{}-green",
-        m.value_of("app").unwrap().to_string(),
-        m.value_of("env").unwrap().to_string(),
-    );
-
-    if !deploy.force {
-        let initial_stats = elb_stats(r.clone(), elb.clone(), 5);
-
-        info!("established baseline performance stats: {:?}", initial_stats);
-        
-        let bsg = list_asg(r.clone(), blue_asg.clone());
-        if bsg.len() == 0 {
-            panic!("there is a problem getting the auto scaling group information");
+        strategy:               Rolling.name().to_string(),
+        max_surge:              Amount::Count(batch),
+        max_unavailable:        Amount::Count(0),
+        min_az_capacity:        DEFAULT_MIN_AZ_CAPACITY,
+        rotation_method:        "manual".to_string(),
+        hooks:                  default_hooks(&u, app_name, env_name),
+        version_scheme:         DEFAULT_SCHEME.to_string(),
+        allow_downgrade:        true,
+        redeploy:               true,
+        approval:               None,
+        approval_timeout:       0,
+    };
+
+    let ctx = DeploymentContext {
+        region:          r,
+        elb:             u["applications"][app_name]["elb"][env_name].as_str().unwrap().to_string(),
+        lc_name:         target.launch_configuration_name.clone(),
+        security_groups: target.security_groups.clone().unwrap_or_default(),
+        deploy,
+        blue_asg,
+        green_asg,
+        blue_asg_info:   bsg[0].clone(),
+        green_asg_info:  gsg[0].clone(),
+        canary_stats:    RefCell::new(Vec::new()),
+        notifier:        notifier(&u, app_name),
+        probe:           probe_config(&u, app_name),
+        // A rollback is usually why the alarms are firing; never gate it on them.
+        alarms:          Vec::new(),
+    };
+
+    info!("rolling back {} from {} ({}) to {} ({})", ctx.green_asg, ctx.green_asg_info.version, ctx.green_asg_info.lc_name, ctx.deploy.version, ctx.lc_name);
+
+    if dry_run() {
+        print_plan_summary(&ctx, &Rolling);
+        print_plan_calls(&Rolling.plan(&ctx));
+        return
+    };
+
+    audited(ctx.region.clone(), &u, deployment_record("rollback", &ctx), |a| {
+        notify(&ctx, "started", &format!("rolling back from {}", ctx.green_asg_info.version));
+        let result = Rolling.execute(&ctx);
+        a.canary_stats = ctx.canary_stats.borrow().clone();
+        if let Err(e) = result {
+            notify(&ctx, "failed", &format!("rollback failed: {}", e));
+            let _ = run_hooks(&ctx, "on_failure", Some(&e));
+            if canary {
+                reset_canary(&ctx);
+            };
+            panic!("ERROR: rollback failed: {}", e);
         };
 
-        let blue_asg_info = bsg[0].clone();
+        info!("rollback of {} to {} is complete", ctx.deploy.environment, ctx.deploy.version);
+        notify(&ctx, "rolled_back", &format!("rolled back from {}", ctx.green_asg_info.version));
+        let _ = run_hooks(&ctx, "on_rollback", None);
+    });
+}
+
+/// Pick the launch configuration to roll back to from `history` (newest
+/// first): the newest one created for version `to` if it is given, otherwise
+/// the newest one older than the ASG's current launch configuration that was
+/// created for a different version than the ASG is tagged with.
+pub fn previous_lc(history: &[LaunchConfiguration], app: &str, env: &str, current: &AutoScaleGroup, to: Option<&str>) -> Option<LaunchConfiguration> {
+    if let Some(v) = to {
+        return history.iter()
+            .find(|lc| lc_version(&lc.launch_configuration_name, app, env) == Some(v.to_string()))
+            .cloned()
+    };
 
-		if blue_asg_info.instance_count != 0 || blue_asg_info.desired_capacity != 0 {
-			info!("current instance count is {} and desired capacity is {}", blue_asg_info.instance_count, blue_asg_info.desired_capacity);
-			panic!("ERROR: blue ASG is not set to 0 instances. Is there another deploy happening?");
-		};
+    let start = match history.iter().position(|lc| lc.launch_configuration_name == current.lc_name) {
+        Some(i) => i + 1,
+        None => 0,
+    };
 
-        updatelc_asg(r.clone(), blue_asg.clone(), lc.clone());
-        update_version_tag(r.clone(), blue_asg.clone(), deploy.clone().version);
+    history[start..].iter()
+        .find(|lc| lc.launch_configuration_name != current.lc_name
+            && lc_version(&lc.launch_configuration_name, app, env) != Some(current.version.clone()))
+        .cloned()
+}
 
-        let in_service = in_service(r.clone(), elb.clone());
+fn create_lc_call(c: &DeploymentContext) -> String {
+    aws_call("autoscaling", "CreateLaunchConfiguration", format!("{} (ami: {})", c.lc_name, c.deploy.ami))
+}
 
-        resize_asg(r.clone(), blue_asg.clone(), 1, 1, 1);
+/// Point the blue ASG at the new launch configuration and scale it to `n`
+/// instances, waiting for all of them to register with the ELB.
+pub fn scale_blue(c: &DeploymentContext, n: i64) -> Result<(), String> {
+    let r = c.region.clone();
 
-        info!("resized blue asg to launch a canary instance, waiting for canary to enter load...");
-        if !wait_for_in_service(r.clone(), blue_asg.clone(), in_service + 1, deploy.healthcheck_timeout * 60) {
-            resize_asg(r.clone(), blue_asg.clone(), 0, 0, 0);
-            info!("resized blue asg to: {}", blue_asg_info.desired_capacity);
+    if c.blue_asg_info.instance_count != 0 || c.blue_asg_info.desired_capacity != 0 {
+        info!("current instance count is {} and desired capacity is {}", c.blue_asg_info.instance_count, c.blue_asg_info.desired_capacity);
+        return Err("blue ASG is not set to 0 instances. Is there another deploy happening?".to_string())
+    };
 
-            updatelc_asg(r.clone(), blue_asg.clone(), blue_asg_info.clone().lc_name);
-            info!("reset launch config to original value: {}", blue_asg_info.clone().lc_name);
+    updatelc_asg(r.clone(), c.blue_asg.clone(), c.lc_name.clone());
+    update_version_tag(r.clone(), c.blue_asg.clone(), c.deploy.version.clone());
 
-            panic!("ERROR: timed out waiting for the canary to register with the ELB");
-        };
+    let in_service = in_service(r.clone(), c.elb.clone());
 
-        info!("canary instance is registered with the ELB and taking traffic. starting a 5 minute monitoring window.");
-        for _s in 1..5 {
-            let canary_wait_stats = elb_stats(r.clone(), elb.clone(), 1);
-            info!("stats: {:?}", canary_wait_stats);
+    resize_asg(r.clone(), c.blue_asg.clone(), n, n, n);
 
-            thread::sleep(time::Duration::from_secs(60));
-        };
+    info!("resized blue asg to {}, waiting for new instances to enter load...", n);
+    if !wait_for_in_service(r.clone(), c.elb.clone(), in_service + n as usize, c.deploy.healthcheck_timeout * 60) {
+        return Err(format!("timed out waiting for {} instance(s) in {} to register with the ELB", n, c.blue_asg))
+    };
+    probe_new_instances(c, &c.blue_asg)?;
+    check_alarms(c, "after launching the canary")?;
 
-        let canary_stats = elb_stats(r.clone(), elb.clone(), 5);
-        info!("canary stats (5 min): {:?}", canary_stats);
+    notify(c, "canary_launched", &format!("{} instance(s) in {} are InService", n, c.blue_asg));
+    run_hooks(c, "after_canary_healthy", None)
+}
 
-        if !deploy.force {
-            info!("error rate: {:.50} max error rate: {:.50}", canary_stats[3], deploy.max_error_rate);
+pub fn scale_blue_calls(c: &DeploymentContext, n: i64) -> Vec<String> {
+    let mut calls = vec![
+        aws_call("autoscaling", "UpdateAutoScalingGroup", format!("{} (launch config: {})", c.blue_asg, c.lc_name)),
+        aws_call("autoscaling", "CreateOrUpdateTags", format!("{} (version: {})", c.blue_asg, c.deploy.version)),
+        aws_call("autoscaling", "UpdateAutoScalingGroup", format!("{} (min: {}, max: {}, desired: {})", c.blue_asg, n, n, n)),
+        format!("wait up to {} minutes for {} more InService instance(s) on {}", c.deploy.healthcheck_timeout, n, c.elb),
+    ];
+    calls.extend(probe_calls(c, &c.blue_asg));
+    calls.extend(check_alarms_calls(c, "after launching the canary"));
+    calls.extend(hook_calls(c, "after_canary_healthy"));
+    calls
+}
 
-            if canary_stats[3] > deploy.max_error_rate {
-                resize_asg(r.clone(), blue_asg.clone(), 0, 0, 0);
-                info!("error rate exceeded MaxErrorRate");
-            };
+pub fn launch_canary(c: &DeploymentContext) -> Result<(), String> {
+    scale_blue(c, 1)?;
+    info!("canary instance is registered with the ELB and taking traffic.");
+    Ok(())
+}
 
-            info!("latency: {:.50} max allowed latency: {:.50}", canary_stats[4], deploy.max_latency);
+/// Watch the ELB for five minutes and compare its error rate and latency
+/// against the deployment thresholds.
+pub fn judge_canary(c: &DeploymentContext) -> Result<(), String> {
+    info!("starting a 5 minute monitoring window.");
+    for _s in 1..5 {
+        let canary_wait_stats = elb_stats(c.region.clone(), c.elb.clone(), 1);
+        info!("stats: {:?}", canary_wait_stats);
+        check_alarms(c, "during the canary")?;
 
-            if canary_stats[4] > deploy.max_latency {
-                resize_asg(r.clone(), blue_asg.clone(), 0, 0, 0);
-                info!("request latency exceeded MaxLatency");
-            };
+        thread::sleep(time::Duration::from_secs(60));
+    };
+
+    let canary_stats = elb_stats(c.region.clone(), c.elb.clone(), 5);
+    info!("canary stats (5 min): {:?}", canary_stats);
+    *c.canary_stats.borrow_mut() = canary_stats.clone();
+
+    let error_rate = canary_stats[3];
+    info!("error rate: {:.50} max error rate: {:.50}", error_rate, c.deploy.max_error_rate);
+
+    info!("latency: {:.50} max allowed latency: {:.50}", canary_stats[4], c.deploy.max_latency);
+
+    let failure = if error_rate > c.deploy.max_error_rate {
+        Some("error rate exceeded MaxErrorRate")
+    } else if canary_stats[4] > c.deploy.max_latency {
+        Some("request latency exceeded MaxLatency")
+    } else {
+        None
+    };
+
+    if let Some(f) = failure {
+        notify(c, "canary_failed", &format!("{} (error rate {:.2}%, latency {:.0} ms)", f, error_rate * 100.0, canary_stats[4] * 1000.0));
+        return Err(f.to_string())
+    };
 
-            info!("canary stats are good. will remove canary and rotate instances");
-        } else {
-            info!("skipping error and latency checks because this is a force deploy");
+    info!("canary stats are good.");
+    notify(c, "canary_passed", &format!("error rate {:.2}%, latency {:.0} ms", error_rate * 100.0, canary_stats[4] * 1000.0));
+    run_hooks(c, "after_canary_judged", None)
+}
+
+pub fn judge_canary_calls(c: &DeploymentContext) -> Vec<String> {
+    let mut calls = vec![
+        aws_call("cloudwatch", "GetMetricStatistics", format!("{} (1 minute stats, every minute for 5 minutes)", c.elb)),
+    ];
+    calls.extend(check_alarms_calls(c, "during the canary (every minute)"));
+    calls.push(format!("fail if the 5 minute error rate exceeds {:.2}% or latency exceeds {:.0} ms", c.deploy.max_error_rate * 100.0, c.deploy.max_latency * 1000.0));
+    calls.extend(hook_calls(c, "after_canary_judged"));
+    calls
+}
+
+/// Scale the blue ASG back down after a successful deployment.
+pub fn remove_canary(c: &DeploymentContext) {
+    resize_asg(c.region.clone(), c.blue_asg.clone(), 0, 0, 0);
+    info!("resized blue asg to 0");
+}
+
+pub fn remove_canary_calls(c: &DeploymentContext) -> Vec<String> {
+    vec![aws_call("autoscaling", "UpdateAutoScalingGroup", format!("{} (min: 0, max: 0, desired: 0)", c.blue_asg))]
+}
+
+/// Move all traffic on the ELB to the blue ASG: detach the green ASG from it
+/// and wait until none of green's instances are InService there.
+pub fn drain_green(c: &DeploymentContext) -> Result<(), String> {
+    detach_elb(c.region.clone(), &c.green_asg, &c.elb);
+    info!("detached {} from {}, waiting for its instances to drain...", c.green_asg, c.elb);
+    if !wait_for_green_on_elb(c, 0) {
+        return Err(format!("timed out waiting for the instances of {} to leave {}", c.green_asg, c.elb))
+    };
+
+    notify(c, "rotation", &format!("switched traffic on {} to {}", c.elb, c.blue_asg));
+    check_alarms(c, "after switching traffic to blue")
+}
+
+pub fn drain_green_calls(c: &DeploymentContext) -> Vec<String> {
+    let mut calls = vec![
+        aws_call("autoscaling", "DetachLoadBalancers", format!("{} ({})", c.green_asg, c.elb)),
+        format!("wait for none of {}'s instances to be InService on {}", c.green_asg, c.elb),
+    ];
+    calls.extend(check_alarms_calls(c, "after switching traffic to blue"));
+    calls
+}
+
+/// Put the green ASG back behind the ELB and wait until every one of its
+/// instances is InService there.
+pub fn attach_green(c: &DeploymentContext) -> Result<(), String> {
+    attach_elb(c.region.clone(), &c.green_asg, &c.elb);
+    let want = list_asg(c.region.clone(), c.green_asg.clone()).into_iter().next().map(|a| a.desired_capacity).unwrap_or(0);
+    info!("attached {} to {}, waiting for {} instance(s) to enter load...", c.green_asg, c.elb, want);
+    if !wait_for_green_on_elb(c, want as usize) {
+        return Err(format!("timed out waiting for {} instance(s) of {} to be InService on {}", want, c.green_asg, c.elb))
+    };
+
+    notify(c, "rotation", &format!("switched traffic on {} back to {}", c.elb, c.green_asg));
+    Ok(())
+}
+
+pub fn attach_green_calls(c: &DeploymentContext) -> Vec<String> {
+    vec![
+        aws_call("autoscaling", "AttachLoadBalancers", format!("{} ({})", c.green_asg, c.elb)),
+        format!("wait for {} of {}'s instances to be InService on {}", c.green_asg_info.desired_capacity, c.green_asg, c.elb),
+    ]
+}
+
+/// Whether the green ASG is currently out of the ELB's traffic, as
+/// `drain_green` leaves it.
+pub fn green_drained(c: &DeploymentContext) -> bool {
+    list_asg(c.region.clone(), c.green_asg.clone()).into_iter().next()
+        .map(|a| !a.elbs.contains(&c.elb))
+        .unwrap_or(false)
+}
+
+/// Wait until exactly `want` of the green ASG's instances are InService on
+/// the ELB.
+fn wait_for_green_on_elb(c: &DeploymentContext, want: usize) -> bool {
+    let now = time::Instant::now();
+    let timeout = c.deploy.healthcheck_timeout * 60;
+
+    while now.elapsed().as_secs() < timeout {
+        let members: Vec<String> = list_asg(c.region.clone(), c.green_asg.clone()).into_iter()
+            .flat_map(|a| a.instances.into_iter().map(|i| i.instance_id))
+            .collect();
+        let count = in_service_ids(c.region.clone(), c.elb.clone()).iter().filter(|id| members.contains(id)).count();
+
+        info!("ELB: {}: want {} of {}'s instances InService, have {}", c.elb, want, c.green_asg, count);
+        if count == want {
+            return true
         };
+        thread::sleep(time::Duration::from_secs(15));
     };
 
-    let gsg = list_asg(r.clone(), green_asg.clone());
-    if gsg.len() == 0 {
-        panic!("there is a problem getting the auto scaling group information");
+    warn!("WARN: timeout while waiting for {} of {}'s instances to be InService on {}", want, c.green_asg, c.elb);
+    false
+}
+
+/// Put the blue ASG back the way it was before the deployment: scaled down,
+/// with its original launch configuration and tags.
+pub fn reset_canary(c: &DeploymentContext) {
+    // `scale_blue` refuses to use a blue ASG that is already in use, so it
+    // belongs to someone else.
+    if c.blue_asg_info.instance_count != 0 || c.blue_asg_info.desired_capacity != 0 {
+        info!("{} was in use before the deployment; leaving it alone", c.blue_asg);
+        return
     };
+    restore_snapshot(c.region.clone(), &AsgSnapshot::of(&c.blue_asg_info));
+    info!("reset {} to its state before the deployment", c.blue_asg);
+}
 
-    let green_asg_info = gsg[0].clone();
+/// Rotation options for replacing the green ASG within `--max-surge` and
+/// `--max-unavailable`.
+pub fn rolling_rotation(c: &DeploymentContext) -> RotationOptions {
+    RotationOptions {
+        max_surge:       c.deploy.max_surge,
+        max_unavailable: c.deploy.max_unavailable,
+        min_az_capacity: c.deploy.min_az_capacity,
+        refresh:         c.deploy.rotation_method == "refresh",
+    }
+}
 
-    updatelc_asg(r.clone(), green_asg.clone(), lc.clone());
-    update_version_tag(r.clone(), green_asg.clone(), deploy.clone().version);
+/// Rotation options for replacing every green instance at once, for
+/// strategies where something else carries the traffic meanwhile.
+pub fn full_rotation(c: &DeploymentContext) -> RotationOptions {
+    RotationOptions {
+        max_surge:       Amount::Count(c.green_asg_info.instance_count as usize),
+        max_unavailable: Amount::Count(0),
+        min_az_capacity: 0.0,
+        refresh:         c.deploy.rotation_method == "refresh",
+    }
+}
+
+pub fn rotate_green(c: &DeploymentContext, o: &RotationOptions) -> Result<(), String> {
+    check_alarms(c, "before the rotation")?;
+    updatelc_asg(c.region.clone(), c.green_asg.clone(), c.lc_name.clone());
+    update_version_tag(c.region.clone(), c.green_asg.clone(), c.deploy.version.clone());
 
     info!("will now rotate all instances in green ASG...");
-    if deploy.strategy == "replacement".to_string() {
-        rotate_instances(r.clone(), green_asg.clone(), green_asg_info.instance_count as usize);
-    } else {
-        rotate_instances(r.clone(), green_asg.clone(), deploy.batch);
-    };
+    let (surge, unavailable) = o.resolve(c.green_asg_info.desired_capacity);
+    notify(c, "rotation", &format!("rotating {} instance(s) in {}, {} at a time", c.green_asg_info.instance_count, c.green_asg, surge + unavailable));
+    rotate_instances(c.region.clone(), c.green_asg.clone(), o, |i, n| {
+        probe_new_instances(c, &c.green_asg)?;
+        check_alarms(c, &format!("after rotation batch {} of {}", i, n))?;
+        notify(c, "rotation", &format!("rotated batch {} of {} in {}", i, n, c.green_asg));
+        Ok(())
+    })?;
+    // Instances launched to refill the group after the last batch.
+    probe_new_instances(c, &c.green_asg)?;
 
     info!("rotated instances in the green ASG");
-}
\ No newline at end of file
+    notify(c, "rotation", &format!("rotated all instances in {}", c.green_asg));
+    run_hooks(c, "after_rotation", None)
+}
+
+pub fn rotate_green_calls(c: &DeploymentContext, o: &RotationOptions) -> Vec<String> {
+    let mut calls = check_alarms_calls(c, "before the rotation");
+    calls.extend(vec![
+        aws_call("autoscaling", "UpdateAutoScalingGroup", format!("{} (launch config: {})", c.green_asg, c.lc_name)),
+        aws_call("autoscaling", "CreateOrUpdateTags", format!("{} (version: {})", c.green_asg, c.deploy.version)),
+    ]);
+    let mut after_batch = probe_calls(c, &c.green_asg);
+    after_batch.extend(check_alarms_calls(c, "after the batch"));
+    calls.extend(rotation_calls(&rotation_plan(&c.green_asg_info, &outdated_instances(&c.green_asg_info, &c.lc_name), o), o, &after_batch));
+    calls.extend(probe_calls(c, &c.green_asg));
+    calls.extend(hook_calls(c, "after_rotation"));
+    calls
+}
+
+/// Return the green ASG to its launch configuration, size and tags from
+/// before the deployment started, and rotate its instances back.  Does
+/// nothing if its launch configuration was never changed.
+pub fn restore_green(c: &DeploymentContext) {
+    let gsg = list_asg(c.region.clone(), c.green_asg.clone());
+    if gsg.is_empty() || gsg[0].lc_name == c.green_asg_info.lc_name {
+        return
+    };
+
+    restore_snapshot(c.region.clone(), &AsgSnapshot::of(&c.green_asg_info));
+    info!("reset green launch config to original value: {}", c.green_asg_info.lc_name);
+
+    if let Err(e) = rotate_instances(c.region.clone(), c.green_asg.clone(), &rolling_rotation(c), |_, _| Ok(())) {
+        panic!("ERROR: could not rotate {} back to {}: {}", c.green_asg, c.green_asg_info.lc_name, e);
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lc(version: &str, created: &str) -> LaunchConfiguration {
+        LaunchConfiguration {
+            launch_configuration_name: format!("web-prod-{}-{}", version, created),
+            created_time:              created.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// Launch configurations for r39 to r42, newest first, as `lc_history`
+    /// returns them.
+    fn history() -> Vec<LaunchConfiguration> {
+        vec![lc("r42", "20261019120000"), lc("r41", "20261019110000"), lc("r41", "20261019100000"), lc("r40", "20261019090000"),
+             lc("r39", "20261019080000")]
+    }
+
+    fn green(lc_name: &str, version: &str) -> AutoScaleGroup {
+        AutoScaleGroup {
+            name:                "web-prod-green".to_string(),
+            min_size:            2,
+            max_size:            4,
+            desired_capacity:    2,
+            instance_count:      2,
+            lc_name:             lc_name.to_string(),
+            launch_template:     None,
+            version:             version.to_string(),
+            instances:           Vec::new(),
+            elbs:                Vec::new(),
+            suspended_processes: Vec::new(),
+            availability_zones:  Vec::new(),
+            subnets:             Vec::new(),
+            tags:                Vec::new(),
+            app_name:            "web".to_string(),
+            env_name:            "prod".to_string(),
+        }
+    }
+
+    fn previous(current: &AutoScaleGroup, to: Option<&str>) -> Option<String> {
+        previous_lc(&history(), "web", "prod", current, to).map(|lc| lc.launch_configuration_name)
+    }
+
+    #[test]
+    fn previous_lc_takes_the_newest_for_to() {
+        let current = green("web-prod-r42-20261019120000", "r42");
+        assert_eq!(previous(&current, Some("r41")), Some("web-prod-r41-20261019110000".to_string()));
+        assert_eq!(previous(&current, Some("r39")), Some("web-prod-r39-20261019080000".to_string()));
+        assert_eq!(previous(&current, Some("r38")), None);
+    }
+
+    #[test]
+    fn previous_lc_skips_the_current_lc_and_version() {
+        let current = green("web-prod-r41-20261019110000", "r41");
+        assert_eq!(previous(&current, None), Some("web-prod-r40-20261019090000".to_string()));
+    }
+
+    #[test]
+    fn previous_lc_looks_past_a_newer_lc_after_a_rollback() {
+        let current = green("web-prod-r40-20261019090000", "r40");
+        assert_eq!(previous(&current, None), Some("web-prod-r39-20261019080000".to_string()));
+    }
+
+    #[test]
+    fn previous_lc_starts_from_the_newest_when_the_current_lc_is_gone() {
+        let current = green("web-prod-r43-20261019130000", "r43");
+        assert_eq!(previous(&current, None), Some("web-prod-r42-20261019120000".to_string()));
+    }
+
+    #[test]
+    fn previous_lc_has_no_candidate_before_the_first_deployment() {
+        let current = green("web-prod-r39-20261019080000", "r39");
+        assert_eq!(previous(&current, None), None);
+        assert_eq!(previous_lc(&[], "web", "prod", &current, None), None);
+    }
+}
@@ -0,0 +1,135 @@
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::process::{Command, Stdio};
+use yaml_rust::Yaml;
+use deployment::DeploymentContext;
+use utils::dry_run;
+
+/// Points in a deployment where hooks can run.  A failing hook at one of the
+/// first three aborts the deployment; failures at the rest are only logged.
+pub const HOOK_POINTS: &[&str] = &[
+    "before_launch_config",
+    "after_canary_healthy",
+    "after_canary_judged",
+    "after_rotation",
+    "on_failure",
+    "on_rollback",
+];
+
+const ABORTING_HOOK_POINTS: &[&str] = &["before_launch_config", "after_canary_healthy", "after_canary_judged"];
+
+/// Shell commands to run at each hook point, in order.
+pub type Hooks = BTreeMap<String, Vec<String>>;
+
+/// Read a `hooks` block mapping hook points to a command or list of commands.
+pub fn parse_hooks(y: &Yaml, errors: &mut Vec<String>) -> Hooks {
+    let mut hooks = Hooks::new();
+    let hash = match y.as_hash() {
+        Some(h) => h,
+        None => {
+            if !y.is_badvalue() && !y.is_null() {
+                errors.push("hooks must map hook points to commands".to_string());
+            };
+            return hooks
+        },
+    };
+
+    for (k, v) in hash {
+        let point = k.as_str().unwrap_or("").to_string();
+        if !HOOK_POINTS.contains(&point.as_str()) {
+            errors.push(format!("unknown hook point {} (available: {})", point, HOOK_POINTS.join(", ")));
+            continue
+        };
+
+        let commands: Vec<Option<String>> = match *v {
+            Yaml::String(ref c) => vec![Some(c.clone())],
+            Yaml::Array(ref a) => a.iter().map(|c| c.as_str().map(|s| s.to_string())).collect(),
+            _ => vec![None],
+        };
+        for c in commands {
+            match c {
+                Some(c) => hooks.entry(point.clone()).or_default().push(c),
+                None => errors.push(format!("hooks.{} must be a command or a list of commands", point)),
+            };
+        };
+    };
+
+    hooks
+}
+
+/// The deployment context handed to hooks, as environment variables.
+pub fn hook_env(c: &DeploymentContext, point: &str, error: Option<&str>) -> Vec<(String, String)> {
+    vec![
+        ("BURNISH_HOOK".to_string(),             point.to_string()),
+        ("BURNISH_APP".to_string(),              c.deploy.application.clone()),
+        ("BURNISH_ENV".to_string(),              c.deploy.environment.clone()),
+        ("BURNISH_REGION".to_string(),           c.region.name().to_string()),
+        ("BURNISH_VERSION".to_string(),          c.deploy.version.clone()),
+        ("BURNISH_PREVIOUS_VERSION".to_string(), c.green_asg_info.version.clone()),
+        ("BURNISH_AMI".to_string(),              c.deploy.ami.clone()),
+        ("BURNISH_LAUNCH_CONFIG".to_string(),    c.lc_name.clone()),
+        ("BURNISH_STRATEGY".to_string(),         c.deploy.strategy.clone()),
+        ("BURNISH_ELB".to_string(),              c.elb.clone()),
+        ("BURNISH_BLUE_ASG".to_string(),         c.blue_asg.clone()),
+        ("BURNISH_GREEN_ASG".to_string(),        c.green_asg.clone()),
+        ("BURNISH_ERROR".to_string(),            error.unwrap_or("").to_string()),
+    ]
+}
+
+/// Run the hooks for `point` in order.  Each gets the deployment context as
+/// `BURNISH_*` environment variables and as a JSON object on stdin.  Returns
+/// an `Err` if a hook at an aborting point fails.
+pub fn run_hooks(c: &DeploymentContext, point: &str, error: Option<&str>) -> Result<(), String> {
+    let commands = match c.deploy.hooks.get(point) {
+        Some(h) => h,
+        None => return Ok(()),
+    };
+
+    if dry_run() {
+        return Ok(())
+    };
+
+    let env = hook_env(c, point, error);
+    let mut context = json!({});
+    for (k, v) in &env {
+        context[k.trim_start_matches("BURNISH_").to_lowercase()] = json!(v);
+    };
+    context["canary_stats"] = json!(*c.canary_stats.borrow());
+
+    for cmd in commands {
+        info!("running {} hook: {}", point, cmd);
+        let status = Command::new("sh")
+            .arg("-c")
+            .arg(cmd)
+            .envs(env.clone())
+            .stdin(Stdio::piped())
+            .spawn()
+            .and_then(|mut child| {
+                if let Some(mut stdin) = child.stdin.take() {
+                    // A hook that exits without reading stdin is not an error.
+                    let _ = stdin.write_all(context.to_string().as_bytes());
+                };
+                child.wait()
+            });
+
+        let failure = match status {
+            Ok(s) if s.success() => continue,
+            Ok(s) => format!("{} hook `{}` exited with {}", point, cmd, s),
+            Err(e) => format!("{} hook `{}` could not be run: {}", point, cmd, e),
+        };
+
+        if ABORTING_HOOK_POINTS.contains(&point) {
+            return Err(failure)
+        };
+        warn!("WARN: {}", failure);
+    };
+
+    Ok(())
+}
+
+/// Plan lines for the hooks that would run at `point`.
+pub fn hook_calls(c: &DeploymentContext, point: &str) -> Vec<String> {
+    c.deploy.hooks.get(point).cloned().unwrap_or_default().iter()
+        .map(|cmd| format!("run {} hook: {}", point, cmd))
+        .collect()
+}
@@ -1,9 +1,127 @@
 use chrono::prelude::*;
-use rusoto_autoscaling::{Autoscaling, AutoscalingClient, CreateLaunchConfigurationType};
+use rusoto_autoscaling::{Autoscaling, AutoscalingClient, CreateLaunchConfigurationType,
+    LaunchConfiguration, LaunchConfigurationNamesType};
+use rusoto_ec2::{Ec2, Ec2Client, DescribeImagesRequest, Filter};
+use utils::{paginate, skip_for_dry_run};
 
-pub fn create_lc(r: rusoto_core::Region, m: &clap::ArgMatches, u: yaml_rust::Yaml) -> String {
+pub fn lc_name(app: &str, env: &str, version: &str) -> String {
+    format!("{}-{}-{}-{}",
+        app,
+        env,
+        version,
+        Utc::now().format("%Y%m%d%H%M%S").to_string()
+    )
+}
+
+/// The version a launch configuration was created for, recovered from the
+/// name `lc_name` gave it: `{app}-{env}-{version}-{14-digit timestamp}`.
+pub fn lc_version(name: &str, app: &str, env: &str) -> Option<String> {
+    let rest = name.strip_prefix(&format!("{}-{}-", app, env))?;
+    let (version, created) = rest.split_at(rest.rfind('-')?);
+    let created = &created[1..];
+    if version.is_empty() || created.len() != 14 || !created.bytes().all(|c| c.is_ascii_digit()) {
+        return None
+    };
+    Some(version.to_string())
+}
+
+/// Every application and environment pair in the universe `u`.
+pub fn lc_owners(u: &yaml_rust::Yaml) -> Vec<(String, String)> {
+    let names = |k: &str| u[k].as_hash().map(|h| h.keys().filter_map(|n| n.as_str()).map(|n| n.to_string()).collect())
+        .unwrap_or_default();
+    let envs: Vec<String> = names("environments");
+    names("applications").into_iter()
+        .flat_map(|a| envs.iter().map(move |e| (a.clone(), e.clone())))
+        .collect()
+}
+
+/// Whether `name` was created for `app` in `env`.  Names are not
+/// unambiguous, since `web-prod-eu-r42-…` parses as version `eu-r42` of
+/// `web` in `prod`, so a name that also parses for one of the `owners` with
+/// a longer prefix, here `web` in `prod-eu`, belongs to that owner.
+pub fn lc_belongs(name: &str, app: &str, env: &str, owners: &[(String, String)]) -> bool {
+    let prefix = app.len() + env.len();
+    lc_version(name, app, env).is_some() && !owners.iter()
+        .any(|(a, e)| a.len() + e.len() > prefix && lc_version(name, a, e).is_some())
+}
+
+/// Every launch configuration created for `app` in `env`, newest first.
+pub fn lc_history(r: rusoto_core::Region, u: &yaml_rust::Yaml, app: &str, env: &str) -> Vec<LaunchConfiguration> {
+    let owners = lc_owners(u);
     let as_client = AutoscalingClient::new(r.to_owned());
+    let mut history = paginate(|token| {
+        let lc_req = LaunchConfigurationNamesType {
+            max_records: Some(100),
+            next_token:  token,
+            ..Default::default()
+        };
+        match as_client.describe_launch_configurations(lc_req).sync() {
+            Ok(k) => (k.launch_configurations, k.next_token),
+            Err(error) => panic!("ERROR: {:?}", error),
+        }
+    });
+    history.retain(|lc| lc_belongs(&lc.launch_configuration_name, app, env, &owners));
+
+    history.sort_by(|a, b| b.created_time.cmp(&a.created_time));
+    history
+}
+
+pub fn security_groups(u: &yaml_rust::Yaml, app: &str, env: &str) -> Vec<String> {
+    let security_groups = u["applications"][app]["security_groups"][env].clone();
+    let mut sg_ids = Vec::new();
+    for s in security_groups {
+        sg_ids.push(s.as_str().unwrap().to_string())
+    };
+    sg_ids
+}
+
+/// Find the newest AMI matching every filter.
+pub fn select_ami(r: rusoto_core::Region, filters: Vec<Filter>) -> String {
+    let ec2_client = Ec2Client::new(r.to_owned());
+    let described: Vec<String> = filters.iter()
+        .map(|f| format!("{}={}", f.name.clone().unwrap_or_default(), f.values.clone().unwrap_or_default().join(",")))
+        .collect();
+    let image_req = DescribeImagesRequest {
+        filters: Some(filters),
+        ..Default::default()
+    };
+
+    // DescribeImages returns every match at once; it has no pagination token.
+    let mut images = match ec2_client.describe_images(image_req).sync() {
+        Ok(k) => k.images.unwrap_or_default(),
+        Err(error) => panic!("ERROR: {:?}", error),
+    };
+
+    if images.is_empty() {
+        panic!("ERROR: no AMI matches {}", described.join(" "));
+    };
+
+    if images.len() > 1 {
+        warn!("WARN: {} AMIs match {}, using the newest", images.len(), described.join(" "));
+    };
 
+    images.sort_by(|a, b| b.creation_date.cmp(&a.creation_date));
+    images[0].image_id.clone().unwrap()
+}
+
+/// Create the launch configuration `lc_req` describes.
+pub fn create_launch_config(r: rusoto_core::Region, lc_req: CreateLaunchConfigurationType) -> Result<(), String> {
+    let lc_name = lc_req.launch_configuration_name.clone();
+    if skip_for_dry_run("autoscaling", "CreateLaunchConfiguration", format!("{} (ami: {})", lc_name, lc_req.image_id.clone().unwrap_or_default())) {
+        return Ok(())
+    };
+
+    let as_client = AutoscalingClient::new(r.to_owned());
+    match as_client.create_launch_configuration(lc_req).sync() {
+        Ok(_a) => {
+            info!("launch configuration {} successfully created", lc_name);
+            Ok(())
+        },
+        Err(error) => Err(format!("could not create launch configuration {}: {:?}", lc_name, error)),
+    }
+}
+
+pub fn create_lc(r: rusoto_core::Region, m: &clap::ArgMatches, u: yaml_rust::Yaml) -> String {
     if u["applications"][m.value_of("app").unwrap()].is_badvalue() {
         panic!("Application {} does not exist in this universe.", m.value_of("app").unwrap().to_string());
     };
@@ -12,18 +130,10 @@ pub fn create_lc(r: rusoto_core::Region, m: &clap::ArgMatches, u: yaml_rust::Yam
         panic!("Environment {} does not exist in this universe.", m.value_of("env").unwrap().to_string());
     };
 
-    let app = &u["applications"][m.value_of("app").unwrap()];
-    let security_groups = app["security_groups"][m.value_of("env").unwrap()].clone();
-    let mut sg_ids = Vec::new();
-    for s in security_groups {
-        sg_ids.push(s.as_str().unwrap().to_string())
-    };
-
-    let lc_name = format!("{}-{}-{}-{}",
-        m.value_of("app").unwrap().to_string(),
-        m.value_of("env").unwrap().to_string(),
-        m.value_of("version").unwrap().to_string(),
-        Utc::today().format("%Y%m%d%H%M%S").to_string()
+    let lc_name = lc_name(
+        m.value_of("app").unwrap(),
+        m.value_of("env").unwrap(),
+        m.value_of("version").unwrap(),
     );
     let lc_req = CreateLaunchConfigurationType {
         launch_configuration_name: lc_name.clone(),
@@ -31,13 +141,58 @@ pub fn create_lc(r: rusoto_core::Region, m: &clap::ArgMatches, u: yaml_rust::Yam
         instance_type: Some(m.value_of("instance-type").unwrap().to_string()),
         iam_instance_profile: Some(m.value_of("iam-profile").unwrap().to_string()),
         user_data: Some(m.value_of("user-data").unwrap().to_string()),
-        security_groups: Some(sg_ids),
+        security_groups: Some(security_groups(&u, m.value_of("app").unwrap(), m.value_of("env").unwrap())),
         ..Default::default()
     };
-    match as_client.create_launch_configuration(lc_req).sync() {
-        Ok(_a) => info!("launch configuration {} successfully created", lc_name.clone().to_string()),
-        Err(error) => eprintln!("ERROR: {:?}", error),
+    if let Err(e) = create_launch_config(r, lc_req) {
+        panic!("ERROR: {}", e);
     };
 
-    return lc_name
-}
\ No newline at end of file
+    lc_name
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn owners(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs.iter().map(|&(a, e)| (a.to_string(), e.to_string())).collect()
+    }
+
+    #[test]
+    fn lc_version_reads_the_version_between_prefix_and_timestamp() {
+        assert_eq!(lc_version("web-prod-r42-20261019090000", "web", "prod"), Some("r42".to_string()));
+        assert_eq!(lc_version("web-prod-1.0.0-rc.1-20261019090000", "web", "prod"), Some("1.0.0-rc.1".to_string()));
+    }
+
+    #[test]
+    fn lc_version_refuses_names_lc_name_did_not_make() {
+        assert_eq!(lc_version("web-staging-r42-20261019090000", "web", "prod"), None);
+        assert_eq!(lc_version("web-prod-r42", "web", "prod"), None);
+        assert_eq!(lc_version("web-prod-r42-2026101909", "web", "prod"), None);
+        assert_eq!(lc_version("web-prod-r42-2026101909000x", "web", "prod"), None);
+        assert_eq!(lc_version("web-prod--20261019090000", "web", "prod"), None);
+        assert_eq!(lc_version("web-prod-manual", "web", "prod"), None);
+    }
+
+    #[test]
+    fn lc_belongs_leaves_names_of_longer_envs_out() {
+        let o = owners(&[("web", "prod"), ("web", "prod-eu")]);
+        assert!(lc_belongs("web-prod-r42-20261019090000", "web", "prod", &o));
+        assert!(!lc_belongs("web-prod-eu-r42-20261019090000", "web", "prod", &o));
+        assert!(lc_belongs("web-prod-eu-r42-20261019090000", "web", "prod-eu", &o));
+    }
+
+    #[test]
+    fn lc_belongs_leaves_names_of_longer_apps_out() {
+        let o = owners(&[("web", "api"), ("web-api", "prod")]);
+        assert!(!lc_belongs("web-api-prod-r42-20261019090000", "web", "api", &o));
+        assert!(lc_belongs("web-api-prod-r42-20261019090000", "web-api", "prod", &o));
+    }
+
+    #[test]
+    fn lc_owners_pairs_every_application_with_every_environment() {
+        let u = yaml_rust::YamlLoader::load_from_str("applications: {web: {}, api: {}}\nenvironments: {prod: {}}").unwrap().remove(0);
+        assert_eq!(lc_owners(&u), owners(&[("web", "prod"), ("api", "prod")]));
+    }
+}
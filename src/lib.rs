@@ -0,0 +1,43 @@
+#[macro_use] extern crate log;
+#[macro_use] extern crate prettytable;
+extern crate clap;
+extern crate http;
+extern crate futures;
+extern crate chrono;
+extern crate timeago;
+extern crate yaml_rust;
+extern crate rusoto_core;
+extern crate rusoto_s3;
+extern crate rusoto_ec2;
+extern crate rusoto_elb;
+extern crate rusoto_autoscaling;
+extern crate rusoto_cloudwatch;
+#[macro_use] extern crate serde_json;
+extern crate hostname;
+extern crate reqwest;
+extern crate serde_urlencoded;
+extern crate xml;
+
+pub mod utils;
+pub mod universe;
+pub mod oneoff;
+pub mod launchconfig;
+pub mod loadbalancer;
+pub mod autoscalegroup;
+pub mod deployment;
+pub mod strategy;
+pub mod audit;
+pub mod manifest;
+pub mod hooks;
+pub mod notify;
+pub mod newrelic;
+pub mod probe;
+pub mod alarms;
+pub mod version;
+pub mod promote;
+pub mod approval;
+pub mod bundle;
+pub mod regions;
+pub mod refresh;
+pub mod processes;
+pub mod snapshot;
@@ -5,8 +5,8 @@ use prettytable::format;
 use prettytable::Table;
 use chrono::{DateTime, Local, SecondsFormat, Duration as ChronoDuration};
 use rusoto_elb::{Elb, ElbClient, DescribeEndPointStateInput};
-use rusoto_ec2::{Ec2, Ec2Client, DescribeInstancesRequest};
 use rusoto_cloudwatch::{CloudWatch, CloudWatchClient, Dimension, GetMetricStatisticsInput};
+use utils::describe_instances;
 
 #[derive(Debug)]
 struct InstanceStatus {
@@ -21,7 +21,6 @@ struct InstanceStatus {
 
 pub fn elb_status(m: &clap::ArgMatches, r: rusoto_core::Region) {
     let elb_client = ElbClient::new(r.to_owned());
-    let ec2_client = Ec2Client::new(r.to_owned());
 
     let elb_state = DescribeEndPointStateInput {
         load_balancer_name: m.value_of("name").unwrap().to_string(),
@@ -32,16 +31,18 @@ pub fn elb_status(m: &clap::ArgMatches, r: rusoto_core::Region) {
 
     let health_results = elb_client.describe_instance_health(elb_state).sync().ok().unwrap();
 
-    for x in health_results.instance_states.unwrap() {
-        let ec2_instance = DescribeInstancesRequest {
-            instance_ids: Some(vec![x.instance_id.clone().unwrap()]),
-            ..Default::default()
+    let states = health_results.instance_states.unwrap();
+    let ids: Vec<String> = states.iter().filter_map(|x| x.instance_id.clone()).collect();
+    let described = describe_instances(r.clone(), &ids);
+
+    for x in states {
+        let instance = match described.iter().find(|i| i.instance_id == x.instance_id) {
+            Some(i) => i.clone(),
+            None => continue,
         };
-        let instance_results = ec2_client.describe_instances(ec2_instance).sync().ok();
-        let instance_reservation = &instance_results.clone().unwrap().reservations.unwrap()[0];
-        let instance_uptime = instance_reservation.clone().instances.unwrap().into_iter().nth(0).unwrap().launch_time.unwrap();
-        let instance_address = instance_reservation.clone().instances.unwrap().into_iter().nth(0).unwrap().private_ip_address.unwrap();
-        let instance_tags = instance_reservation.clone().instances.unwrap().into_iter().nth(0).unwrap().tags.unwrap();
+        let instance_uptime = instance.launch_time.unwrap();
+        let instance_address = instance.private_ip_address.unwrap();
+        let instance_tags = instance.tags.unwrap();
         let mut instance_name: std::string::String = "".to_string();
         let mut instance_version: std::string::String = "".to_string();
         let mut instance_asg: std::string::String = "".to_string();
@@ -185,23 +186,25 @@ pub fn elb_stats_cmd(r: rusoto_core::Region, m: &clap::ArgMatches) {
 }
 
 pub fn in_service(r: rusoto_core::Region, n: String) -> usize {
+    in_service_ids(r, n).len()
+}
+
+/// IDs of the instances that are InService on ELB `n`.
+pub fn in_service_ids(r: rusoto_core::Region, n: String) -> Vec<String> {
     let elb_client = ElbClient::new(r.to_owned());
     let health_params = DescribeEndPointStateInput {
         load_balancer_name: n,
         ..Default::default()
     };
 
+    // DescribeInstanceHealth returns every instance at once; it has no
+    // pagination token.
     let instances = elb_client.describe_instance_health(health_params).sync().ok();
 
-    let mut count = 0;
-    let instance_states = instances.unwrap().instance_states.unwrap();
-    for i in instance_states {
-        if i.state == Some("InService".to_string()) {
-            count += 1;
-        }
-    };
-
-    return count as usize
+    instances.unwrap().instance_states.unwrap().into_iter()
+        .filter(|i| i.state == Some("InService".to_string()))
+        .filter_map(|i| i.instance_id)
+        .collect()
 }
 
 pub fn wait_for_in_service(r: rusoto_core::Region, n: String, i: usize, t: u64 ) -> bool {
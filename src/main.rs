@@ -1,27 +1,8 @@
-#[macro_use] extern crate log;
 #[macro_use] extern crate clap;
-#[macro_use] extern crate prettytable;
-extern crate http;
-extern crate futures;
-extern crate chrono;
-extern crate timeago;
-extern crate yaml_rust;
-extern crate rusoto_core;
-extern crate rusoto_s3;
-extern crate rusoto_ec2;
-extern crate rusoto_elb;
-extern crate rusoto_autoscaling;
-extern crate rusoto_cloudwatch;
+extern crate burnish;
 
 use clap::App;
-
-pub mod utils;
-pub mod universe;
-pub mod oneoff;
-pub mod launchconfig;
-pub mod loadbalancer;
-pub mod autoscalegroup;
-pub mod deployment;
+use burnish::{utils, universe, oneoff, launchconfig, loadbalancer, autoscalegroup, processes, snapshot, deployment, audit, promote};
 
 fn main() {
     let yaml = load_yaml!("cli.yml");
@@ -38,6 +19,8 @@ fn main() {
         Err(e) => e,
     };
 
+    utils::set_dry_run(matches.is_present("dry-run"));
+
     let universe = universe::get_universe(universe_file.to_string(), region.clone());
 
     if let Some(matches) = matches.subcommand_matches("oneoff") {
@@ -66,6 +49,21 @@ fn main() {
         if let Some(sub_m) = matches.subcommand_matches("list") {
             autoscalegroup::list_asg_cmd(region.clone(), sub_m);
         };
+        if let Some(sub_m) = matches.subcommand_matches("show") {
+            autoscalegroup::show_asg_cmd(region.clone(), sub_m);
+        };
+        if let Some(sub_m) = matches.subcommand_matches("suspend") {
+            processes::suspend_processes_cmd(region.clone(), sub_m, universe.clone());
+        };
+        if let Some(sub_m) = matches.subcommand_matches("resume") {
+            processes::resume_processes_cmd(region.clone(), sub_m, universe.clone());
+        };
+        if let Some(sub_m) = matches.subcommand_matches("snapshot") {
+            snapshot::snapshot_cmd(region.clone(), sub_m);
+        };
+        if let Some(sub_m) = matches.subcommand_matches("restore") {
+            snapshot::restore_cmd(region.clone(), sub_m, universe.clone());
+        };
         if let Some(sub_m) = matches.subcommand_matches("create") {
             autoscalegroup::create_asg_cmd(region.clone(), sub_m, universe.clone());
         };
@@ -73,10 +71,10 @@ fn main() {
             autoscalegroup::destroy_asg_cmd(region.clone(), sub_m);
         };
         if let Some(sub_m) = matches.subcommand_matches("resize") {
-            autoscalegroup::resize_asg_cmd(region.clone(), sub_m);
+            autoscalegroup::resize_asg_cmd(region.clone(), sub_m, universe.clone());
         };
         if let Some(sub_m) = matches.subcommand_matches("rotate") {
-            autoscalegroup::rotate_instances_cmd(region.clone(), sub_m);
+            autoscalegroup::rotate_instances_cmd(region.clone(), sub_m, universe.clone());
         };
         if let Some(sub_m) = matches.subcommand_matches("updatelc") {
             autoscalegroup::updatelc_asg_cmd(region.clone(), sub_m);
@@ -87,9 +85,21 @@ fn main() {
         if let Some(sub_m) = matches.subcommand_matches("do") {
             deployment::do_deployment_cmd(region.clone(), sub_m, universe.clone());
         };
-        //if let Some(sub_m) = matches.subcommand_matches("mark") {
-        //    deployment::mark_deployment_cmd(region.clone(), sub_m, universe.clone());
-        //};
+        if let Some(sub_m) = matches.subcommand_matches("plan") {
+            deployment::plan_deployment_cmd(region.clone(), sub_m, universe.clone());
+        };
+        if let Some(sub_m) = matches.subcommand_matches("rollback") {
+            deployment::rollback_deployment_cmd(region.clone(), sub_m, universe.clone());
+        };
+        if let Some(sub_m) = matches.subcommand_matches("history") {
+            audit::history_cmd(region.clone(), sub_m, universe.clone());
+        };
+        if let Some(sub_m) = matches.subcommand_matches("promote") {
+            promote::promote_cmd(region.clone(), sub_m, universe.clone());
+        };
+        if let Some(sub_m) = matches.subcommand_matches("mark") {
+            deployment::mark_deployment_cmd(region.clone(), sub_m, universe.clone());
+        };
     };
 
 }
@@ -0,0 +1,529 @@
+use std::collections::BTreeMap;
+use yaml_rust::Yaml;
+use rusoto_ec2::Filter;
+use deployment::Deployment;
+use hooks::{Hooks, parse_hooks};
+use autoscalegroup::{Amount, DEFAULT_MIN_AZ_CAPACITY, ROTATION_METHODS, batch_sizes};
+use launchconfig::select_ami;
+use strategy::StrategyRegistry;
+use universe::load_yaml;
+use version::{DEFAULT_SCHEME, SCHEMES, parse_version};
+
+/// Every setting a deployment can take, by its `deployment do` flag name.
+pub const SETTINGS: &[&str] = &[
+    "app", "env", "version", "ami", "instance-type", "iam-profile", "user-data",
+    "strategy", "batch", "max-surge", "max-unavailable", "min-az-capacity", "rotation-method", "force", "max-latency", "max-error-rate", "timeout",
+    "version-scheme", "allow-downgrade", "redeploy", "approval", "approval-timeout",
+];
+
+/// Settings given on the command line as flags without values.
+const FLAGS: &[&str] = &["force", "allow-downgrade", "redeploy"];
+
+/// Deployment settings keyed by flag name.  Values come from, in increasing
+/// order of precedence: `deployment do` flag defaults, the environment's and
+/// then the application's `deploy_defaults` in the universe, the manifest,
+/// and flags given on the command line.
+pub type Settings = BTreeMap<String, Yaml>;
+
+/// Gather settings for `deployment do`, along with any problems found in the
+/// manifest or universe defaults so they can be reported with the rest.
+/// `overrides` take precedence over everything, including the command line.
+pub fn deployment_settings(r: rusoto_core::Region, m: &clap::ArgMatches, u: &Yaml, overrides: Settings) -> (Settings, Vec<String>) {
+    let mut errors = Vec::new();
+
+    let manifest = match m.value_of("manifest") {
+        Some(path) => flatten_manifest(&load_yaml(path.to_string(), r, "deployment manifest"), &mut errors),
+        None => Settings::new(),
+    };
+
+    let mut cli = Settings::new();
+    let mut cli_defaults = Settings::new();
+    for k in SETTINGS {
+        if FLAGS.contains(k) {
+            if m.is_present(k) {
+                cli.insert(k.to_string(), Yaml::Boolean(true));
+            };
+            continue
+        };
+        if let Some(v) = m.value_of(k) {
+            if m.occurrences_of(k) > 0 {
+                cli.insert(k.to_string(), Yaml::String(v.to_string()));
+            } else {
+                cli_defaults.insert(k.to_string(), Yaml::String(v.to_string()));
+            };
+        };
+    };
+
+    merge(&mut cli, overrides);
+
+    let pick = |k: &str| cli.get(k).or_else(|| manifest.get(k)).and_then(|v| v.as_str()).map(|v| v.to_string());
+    let app = pick("app");
+    let env = pick("env");
+
+    let mut settings = cli_defaults;
+    if let Some(ref e) = env {
+        merge(&mut settings, flatten_manifest(&u["environments"][e.as_str()]["deploy_defaults"], &mut errors));
+    };
+    if let Some(ref a) = app {
+        merge(&mut settings, flatten_manifest(&u["applications"][a.as_str()]["deploy_defaults"], &mut errors));
+    };
+    merge(&mut settings, manifest);
+    merge(&mut settings, cli);
+
+    (settings, errors)
+}
+
+fn merge(into: &mut Settings, from: Settings) {
+    for (k, v) in from {
+        into.insert(k, v);
+    };
+}
+
+/// Turn a manifest (or a `deploy_defaults` block) into flat settings.  Keys
+/// may use dashes or underscores, and may be grouped into `instance`,
+/// `strategy`, `thresholds` and `approval` sections.  A `hooks` block is kept whole.
+pub fn flatten_manifest(y: &Yaml, errors: &mut Vec<String>) -> Settings {
+    let mut settings = Settings::new();
+    let hash = match y.as_hash() {
+        Some(h) => h,
+        None => {
+            if !y.is_badvalue() && !y.is_null() {
+                errors.push("manifest must be a mapping of settings".to_string());
+            };
+            return settings
+        },
+    };
+
+    for (k, v) in hash {
+        let key = match k.as_str() {
+            Some(s) => s.replace('_', "-"),
+            None => {
+                errors.push(format!("manifest key {:?} is not a string", k));
+                continue
+            },
+        };
+
+        match (key.as_str(), v) {
+            ("instance", &Yaml::Hash(_)) => flatten_section(&key, v, &[
+                ("type", "instance-type"), ("iam-profile", "iam-profile"), ("user-data", "user-data"),
+            ], &mut settings, errors),
+            ("strategy", &Yaml::Hash(_)) => flatten_section(&key, v, &[
+                ("name", "strategy"), ("batch", "batch"), ("max-surge", "max-surge"), ("max-unavailable", "max-unavailable"),
+                ("min-az-capacity", "min-az-capacity"), ("rotation-method", "rotation-method"),
+            ], &mut settings, errors),
+            ("approval", &Yaml::Hash(_)) => flatten_section(&key, v, &[
+                ("via", "approval"), ("timeout", "approval-timeout"),
+            ], &mut settings, errors),
+            ("thresholds", &Yaml::Hash(_)) => flatten_section(&key, v, &[
+                ("max-latency", "max-latency"), ("max-error-rate", "max-error-rate"), ("timeout", "timeout"),
+            ], &mut settings, errors),
+            ("hooks", _) => {
+                settings.insert(key.clone(), v.clone());
+            },
+            (k, _) if SETTINGS.contains(&k) => {
+                settings.insert(key.clone(), v.clone());
+            },
+            (k, _) => errors.push(format!("unknown setting {}", k)),
+        };
+    };
+
+    settings
+}
+
+/// Copy the keys of a manifest section into `settings` under the setting
+/// names `names` maps them to.
+fn flatten_section(section: &str, y: &Yaml, names: &[(&str, &str)], settings: &mut Settings, errors: &mut Vec<String>) {
+    for (k, v) in y.as_hash().unwrap() {
+        let key = k.as_str().unwrap_or("").replace('_', "-");
+        match names.iter().find(|n| n.0 == key) {
+            Some(n) => { settings.insert(n.1.to_string(), v.clone()); },
+            None => errors.push(format!("unknown setting {}.{}", section, key)),
+        };
+    };
+}
+
+fn as_string(v: &Yaml) -> Option<String> {
+    match *v {
+        Yaml::String(ref s) => Some(s.clone()),
+        Yaml::Integer(i) => Some(i.to_string()),
+        Yaml::Real(ref s) => Some(s.clone()),
+        Yaml::Boolean(b) => Some(b.to_string()),
+        _ => None,
+    }
+}
+
+/// Check every setting and build the `Deployment` they describe.  All
+/// problems, including the `errors` already found, are reported together
+/// rather than one at a time.
+pub fn build_deployment(r: rusoto_core::Region, settings: &Settings, mut errors: Vec<String>, u: &Yaml, s: &StrategyRegistry) -> Deployment {
+
+    let app = required(settings, "app", &mut errors);
+    let env = required(settings, "env", &mut errors);
+    let version = required(settings, "version", &mut errors);
+    let instance_type = required(settings, "instance-type", &mut errors);
+    let iam_profile = required(settings, "iam-profile", &mut errors);
+    let user_data = required(settings, "user-data", &mut errors);
+    let strategy = required(settings, "strategy", &mut errors);
+    let batch = required(settings, "batch", &mut errors);
+    let max_latency = required(settings, "max-latency", &mut errors);
+    let max_error_rate = required(settings, "max-error-rate", &mut errors);
+    let timeout = required(settings, "timeout", &mut errors);
+
+    if !app.is_empty() && u["applications"][app.as_str()].is_badvalue() {
+        errors.push(format!("Application {} does not exist in this universe.", app));
+    };
+
+    if !env.is_empty() && u["environments"][env.as_str()].is_badvalue() {
+        errors.push(format!("Environment {} does not exist in this universe.", env));
+    };
+
+    if version.chars().count() > 255 {
+        errors.push("max length for version string is 255 chars.".to_string());
+    };
+
+    if !strategy.is_empty() && s.get(&strategy).is_none() {
+        errors.push(format!("Deployment strategy {} is not registered (available: {}).", strategy, s.names().join(", ")));
+    };
+
+    let batch = parse_setting::<usize>("batch", &batch, &mut errors);
+    let max_latency = parse_setting::<f64>("max-latency", &max_latency, &mut errors);
+    let max_error_rate = parse_setting::<f64>("max-error-rate", &max_error_rate, &mut errors);
+    let timeout = parse_setting::<u64>("timeout", &timeout, &mut errors);
+
+    let hooks = parse_hooks(settings.get("hooks").unwrap_or(&Yaml::BadValue), &mut errors);
+
+    let force = parse_flag(settings, "force", &mut errors);
+    let allow_downgrade = parse_flag(settings, "allow-downgrade", &mut errors);
+    let redeploy = parse_flag(settings, "redeploy", &mut errors);
+
+    let approval = match settings.get("approval").and_then(as_string) {
+        Some(ref a) if a == "none" => None,
+        a => a,
+    };
+    let min_az_capacity = match settings.get("min-az-capacity").and_then(as_string) {
+        Some(c) => parse_setting::<f64>("min-az-capacity", &c, &mut errors),
+        None => DEFAULT_MIN_AZ_CAPACITY * 100.0,
+    };
+    if !(0.0..=100.0).contains(&min_az_capacity) {
+        errors.push(format!("min-az-capacity must be a percentage, not {}", min_az_capacity));
+    };
+
+    let amount = |k: &str, errors: &mut Vec<String>| settings.get(k).and_then(as_string).and_then(|v| match Amount::parse(&v) {
+        Ok(a) => Some(a),
+        Err(e) => {
+            errors.push(format!("{}: {}", k, e));
+            None
+        },
+    });
+    let max_surge = amount("max-surge", &mut errors);
+    let max_unavailable = amount("max-unavailable", &mut errors);
+    let (max_surge, max_unavailable) = batch_sizes(batch, max_surge, max_unavailable);
+
+    let rotation_method = settings.get("rotation-method").and_then(as_string).unwrap_or_else(|| "manual".to_string());
+    if !ROTATION_METHODS.contains(&rotation_method.as_str()) {
+        errors.push(format!("unknown rotation method {} (available: {})", rotation_method, ROTATION_METHODS.join(", ")));
+    };
+
+    let approval_timeout = match settings.get("approval-timeout").and_then(as_string) {
+        Some(t) => parse_setting::<u64>("approval-timeout", &t, &mut errors),
+        None => 60,
+    };
+
+    let version_scheme = settings.get("version-scheme").and_then(as_string).unwrap_or_else(|| DEFAULT_SCHEME.to_string());
+    if !SCHEMES.contains(&version_scheme.as_str()) {
+        errors.push(format!("unknown version scheme {} (available: {})", version_scheme, SCHEMES.join(", ")));
+    } else if !version.is_empty() {
+        if let Err(e) = parse_version(&version_scheme, &version) {
+            errors.push(e);
+        };
+    };
+
+    let (ami_id, ami_filters) = match settings.get("ami") {
+        Some(Yaml::String(id)) => (Some(id.clone()), Vec::new()),
+        Some(selector) => (None, ami_selector(selector, &mut errors)),
+        None => (None, vec![
+            Filter { name: Some("tag:app".to_string()), values: Some(vec![app.clone()]) },
+            Filter { name: Some("tag:version".to_string()), values: Some(vec![version.clone()]) },
+        ]),
+    };
+
+    if !errors.is_empty() {
+        panic!("ERROR: invalid deployment settings:\n  - {}", errors.join("\n  - "));
+    };
+
+    let ami = match ami_id {
+        Some(id) => id,
+        None => select_ami(r, ami_filters),
+    };
+
+    Deployment {
+        application:            app,
+        environment:            env,
+        iam_profile,
+        instance_type,
+        version,
+        ami,
+        force,
+        max_latency:            max_latency / 1000.0,
+        max_error_rate:         max_error_rate / 100.0,
+        user_data,
+        healthcheck_timeout:    timeout,
+        strategy,
+        max_surge,
+        max_unavailable,
+        min_az_capacity:        min_az_capacity / 100.0,
+        rotation_method,
+        hooks,
+        version_scheme,
+        allow_downgrade,
+        redeploy,
+        approval,
+        approval_timeout,
+    }
+}
+
+/// The hooks set in the universe's `deploy_defaults` for `app` in `env`, for
+/// commands such as `deployment rollback` that take no manifest.
+pub fn default_hooks(u: &Yaml, app: &str, env: &str) -> Hooks {
+    let mut errors = Vec::new();
+    let mut settings = flatten_manifest(&u["environments"][env]["deploy_defaults"], &mut errors);
+    merge(&mut settings, flatten_manifest(&u["applications"][app]["deploy_defaults"], &mut errors));
+    let hooks = parse_hooks(settings.get("hooks").unwrap_or(&Yaml::BadValue), &mut errors);
+
+    if !errors.is_empty() {
+        panic!("ERROR: invalid deployment settings:\n  - {}", errors.join("\n  - "));
+    };
+    hooks
+}
+
+fn required(settings: &Settings, k: &str, errors: &mut Vec<String>) -> String {
+    match settings.get(k).and_then(as_string) {
+        Some(v) => v,
+        None => {
+            errors.push(format!("{} is required", k));
+            String::new()
+        },
+    }
+}
+
+fn parse_flag(settings: &Settings, k: &str, errors: &mut Vec<String>) -> bool {
+    match settings.get(k) {
+        None => false,
+        Some(v) => match as_string(v).map(|f| f.parse::<bool>()) {
+            Some(Ok(f)) => f,
+            _ => { errors.push(format!("{} must be true or false", k)); false },
+        },
+    }
+}
+
+fn parse_setting<T: std::str::FromStr + Default>(k: &str, v: &str, errors: &mut Vec<String>) -> T {
+    if v.is_empty() {
+        return T::default()
+    };
+
+    match v.parse::<T>() {
+        Ok(p) => p,
+        Err(_) => {
+            errors.push(format!("{} must be a number, not {}", k, v));
+            T::default()
+        },
+    }
+}
+
+/// Turn an AMI selector (`tags` and/or `name`) into DescribeImages filters.
+fn ami_selector(y: &Yaml, errors: &mut Vec<String>) -> Vec<Filter> {
+    let mut filters = Vec::new();
+    let hash = match y.as_hash() {
+        Some(h) => h,
+        None => {
+            errors.push("ami must be an AMI ID or a selector with tags and/or name".to_string());
+            return filters
+        },
+    };
+
+    for (k, v) in hash {
+        match k.as_str() {
+            Some("tags") => {
+                for (tk, tv) in v.as_hash().cloned().unwrap_or_default() {
+                    match (as_string(&tk), as_string(&tv)) {
+                        (Some(tk), Some(tv)) => filters.push(Filter { name: Some(format!("tag:{}", tk)), values: Some(vec![tv]) }),
+                        _ => errors.push("ami.tags must map tag names to values".to_string()),
+                    };
+                };
+            },
+            Some("name") => match as_string(v) {
+                Some(n) => filters.push(Filter { name: Some("name".to_string()), values: Some(vec![n]) }),
+                None => errors.push("ami.name must be a string".to_string()),
+            },
+            _ => errors.push(format!("unknown setting ami.{}", as_string(k).unwrap_or_default())),
+        };
+    };
+
+    if filters.is_empty() {
+        errors.push("ami selector must give tags and/or name".to_string());
+    };
+
+    filters
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::panic::{self, AssertUnwindSafe};
+    use yaml_rust::YamlLoader;
+    use audit::panic_message;
+
+    fn yaml(s: &str) -> Yaml {
+        YamlLoader::load_from_str(s).unwrap().remove(0)
+    }
+
+    const UNIVERSE: &str = "
+environments:
+  prod:
+    deploy_defaults:
+      batch: 2
+      timeout: 10
+      max_latency: 100
+      max_error_rate: 1
+applications:
+  web:
+    deploy_defaults:
+      timeout: 20
+      max_latency: 200
+      max_error_rate: 2
+      instance:
+        type: t3.small
+";
+
+    /// The `deployment do` matches for `args`, as `main` would parse them.
+    fn matches(args: &[&str]) -> clap::ArgMatches<'static> {
+        let cli = Box::leak(Box::new(clap::YamlLoader::load_from_str(include_str!("cli.yml")).unwrap().remove(0)));
+        let mut argv = vec!["burnish", "deployment", "do"];
+        argv.extend_from_slice(args);
+        let m = clap::App::from_yaml(cli).get_matches_from(argv);
+        m.subcommand_matches("deployment").unwrap().subcommand_matches("do").unwrap().clone()
+    }
+
+    fn manifest_file(name: &str, body: &str) -> String {
+        let path = std::env::temp_dir().join(format!("burnish-{}-{}.yml", name, std::process::id()));
+        std::fs::write(&path, body).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    fn setting(settings: &Settings, k: &str) -> String {
+        settings.get(k).and_then(as_string).unwrap_or_default()
+    }
+
+    #[test]
+    fn settings_take_the_most_specific_source() {
+        let path = manifest_file("precedence", "
+app: web
+version: r40
+max_latency: 300
+max_error_rate: 3
+instance:
+  type: m5.large
+");
+        let m = matches(&["--manifest", &path, "--env", "prod", "--version", "r41", "--max-error-rate", "4"]);
+        let mut overrides = Settings::new();
+        overrides.insert("version".to_string(), Yaml::String("r42".to_string()));
+        let (settings, errors) = deployment_settings(rusoto_core::Region::UsEast1, &m, &yaml(UNIVERSE), overrides);
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert_eq!(setting(&settings, "strategy"), "rolling");      // flag default
+        assert_eq!(setting(&settings, "batch"), "2");               // env deploy_defaults
+        assert_eq!(setting(&settings, "timeout"), "20");            // app deploy_defaults
+        assert_eq!(setting(&settings, "max-latency"), "300");       // manifest
+        assert_eq!(setting(&settings, "instance-type"), "m5.large"); // manifest
+        assert_eq!(setting(&settings, "max-error-rate"), "4");      // command line
+        assert_eq!(setting(&settings, "version"), "r42");           // overrides
+        assert_eq!(setting(&settings, "app"), "web");
+        assert_eq!(setting(&settings, "env"), "prod");
+    }
+
+    #[test]
+    fn manifests_may_group_settings_and_use_underscores() {
+        let mut errors = Vec::new();
+        let settings = flatten_manifest(&yaml("
+instance: {type: m5.large, iam_profile: web}
+strategy: {name: canary, max_surge: 25%, rotation_method: refresh}
+thresholds: {max_latency: 250, timeout: 15}
+approval: {via: slack, timeout: 30}
+allow_downgrade: true
+"), &mut errors);
+
+        assert!(errors.is_empty(), "{:?}", errors);
+        let flat: Vec<(String, String)> = settings.iter().map(|(k, v)| (k.clone(), as_string(v).unwrap())).collect();
+        let pairs = |p: &[(&str, &str)]| p.iter().map(|&(k, v)| (k.to_string(), v.to_string())).collect::<Vec<_>>();
+        assert_eq!(flat, pairs(&[
+            ("allow-downgrade", "true"), ("approval", "slack"), ("approval-timeout", "30"), ("iam-profile", "web"),
+            ("instance-type", "m5.large"), ("max-latency", "250"), ("max-surge", "25%"), ("rotation-method", "refresh"),
+            ("strategy", "canary"), ("timeout", "15"),
+        ]));
+    }
+
+    #[test]
+    fn unknown_settings_are_reported() {
+        let mut errors = Vec::new();
+        let settings = flatten_manifest(&yaml("
+colour: red
+version: r42
+strategy: {name: rolling, speed: fast}
+thresholds: {max_cpu: 80}
+"), &mut errors);
+
+        assert_eq!(errors, vec!["unknown setting colour", "unknown setting strategy.speed", "unknown setting thresholds.max-cpu"]);
+        assert_eq!(settings.keys().collect::<Vec<_>>(), vec!["strategy", "version"]);
+    }
+
+    #[test]
+    fn a_manifest_must_be_a_mapping() {
+        let mut errors = Vec::new();
+        assert!(flatten_manifest(&yaml("- app: web"), &mut errors).is_empty());
+        assert_eq!(errors, vec!["manifest must be a mapping of settings"]);
+
+        let mut errors = Vec::new();
+        assert!(flatten_manifest(&Yaml::BadValue, &mut errors).is_empty());
+        assert!(errors.is_empty());
+    }
+
+    #[test]
+    fn build_deployment_converts_settings() {
+        let m = matches(&["--app", "web", "--env", "prod", "--version", "r42", "--ami", "ami-0123", "--iam-profile", "web",
+                          "--user-data", "", "--max-surge", "25%"]);
+        let u = yaml(UNIVERSE);
+        let (settings, errors) = deployment_settings(rusoto_core::Region::UsEast1, &m, &u, Settings::new());
+        let d = build_deployment(rusoto_core::Region::UsEast1, &settings, errors, &u, &StrategyRegistry::default());
+
+        assert_eq!((d.application.as_str(), d.environment.as_str(), d.version.as_str()), ("web", "prod", "r42"));
+        assert_eq!((d.ami.as_str(), d.instance_type.as_str()), ("ami-0123", "t3.small"));
+        assert_eq!((d.max_latency, d.max_error_rate, d.healthcheck_timeout), (0.2, 0.02, 20));
+        assert_eq!(d.max_surge, Amount::Percent(25.0));
+        assert_eq!(d.min_az_capacity, DEFAULT_MIN_AZ_CAPACITY);
+        assert_eq!((d.strategy.as_str(), d.rotation_method.as_str(), d.version_scheme.as_str()), ("rolling", "manual", DEFAULT_SCHEME));
+    }
+
+    #[test]
+    fn build_deployment_reports_every_problem_at_once() {
+        let mut settings = Settings::new();
+        for &(k, v) in &[("app", "shop"), ("env", "prod"), ("instance-type", "t3.small"), ("iam-profile", "web"),
+                         ("user-data", ""), ("strategy", "rolling"), ("batch", "two"), ("max-latency", "200"),
+                         ("max-error-rate", "1"), ("timeout", "10"), ("ami", "ami-0123"), ("rotation-method", "rebuild")] {
+            settings.insert(k.to_string(), Yaml::String(v.to_string()));
+        };
+        let errors = vec!["unknown setting colour".to_string()];
+
+        let e = panic::catch_unwind(AssertUnwindSafe(|| {
+            build_deployment(rusoto_core::Region::UsEast1, &settings, errors, &yaml(UNIVERSE), &StrategyRegistry::default())
+        })).unwrap_err();
+        assert_eq!(panic_message(&*e), [
+            "ERROR: invalid deployment settings:",
+            "unknown setting colour",
+            "version is required",
+            "Application shop does not exist in this universe.",
+            "batch must be a number, not two",
+            "unknown rotation method rebuild (available: manual, refresh)",
+        ].join("\n  - "));
+    }
+}
@@ -0,0 +1,57 @@
+use std::time;
+use utils::skip_for_dry_run;
+
+/// Where deployment markers are posted unless the universe sets
+/// `new_relic_api_url`.
+pub const DEFAULT_API_URL: &str = "https://api.newrelic.com";
+
+#[derive(Debug, Clone, Default)]
+pub struct Marker {
+    pub app_id:      std::string::String,
+    pub revision:    std::string::String,
+    pub description: std::string::String,
+    pub user:        std::string::String,
+    pub changelog:   std::string::String,
+}
+
+/// The New Relic application ID for `app` in `env`, from the universe's
+/// `new_relic_app_id`.
+pub fn app_id(u: &yaml_rust::Yaml, app: &str, env: &str) -> Option<String> {
+    let id = &u["applications"][app]["new_relic_app_id"][env];
+    id.as_str().map(|s| s.to_string()).or_else(|| id.as_i64().map(|i| i.to_string()))
+}
+
+pub fn api_url(u: &yaml_rust::Yaml) -> String {
+    u["new_relic_api_url"].as_str().unwrap_or(DEFAULT_API_URL).trim_end_matches('/').to_string()
+}
+
+/// Record a deployment marker with the New Relic REST API.
+pub fn mark_deployment(api_url: &str, api_key: &str, mk: &Marker) -> Result<(), String> {
+    let url = format!("{}/v2/applications/{}/deployments.json", api_url, mk.app_id);
+    if skip_for_dry_run("newrelic", "CreateDeployment", format!("{} (revision: {})", url, mk.revision)) {
+        return Ok(())
+    };
+
+    let body = json!({
+        "deployment": {
+            "revision": mk.revision,
+            "description": mk.description,
+            "user": mk.user,
+            "changelog": mk.changelog,
+        }
+    });
+
+    let client = reqwest::Client::builder()
+        .timeout(time::Duration::from_secs(10))
+        .build()
+        .map_err(|e| format!("{:?}", e))?;
+
+    match client.post(&url).header("X-Api-Key", api_key).json(&body).send() {
+        Ok(ref res) if res.status().is_success() => {
+            info!("marked deployment of {} in New Relic application {}", mk.revision, mk.app_id);
+            Ok(())
+        },
+        Ok(res) => Err(format!("New Relic returned {} for {}", res.status(), url)),
+        Err(error) => Err(format!("could not reach New Relic at {}: {}", url, error)),
+    }
+}
@@ -0,0 +1,175 @@
+use std::{thread, time};
+use serde_json::Value;
+use yaml_rust::Yaml;
+use deployment::DeploymentContext;
+use utils::dry_run;
+
+/// Deployment events webhooks can subscribe to.
+pub const EVENTS: &[&str] = &[
+    "started",
+    "canary_launched",
+    "canary_passed",
+    "canary_failed",
+    "awaiting_approval",
+    "rotation",
+    "completed",
+    "failed",
+    "rolled_back",
+];
+
+/// Payload formats a webhook can receive.
+pub const FORMATS: &[&str] = &["json", "slack", "teams"];
+
+#[derive(Debug, Clone, Default)]
+pub struct Webhook {
+    pub url:    std::string::String,
+    pub format: std::string::String,
+    /// Events to send; every event when empty.
+    pub events: Vec<std::string::String>,
+}
+
+/// Where and how to deliver deployment notifications.  Each webhook gets
+/// `timeout` seconds per attempt and `retries` more attempts, so a dead
+/// webhook delays a deployment by a bounded amount and never fails it.
+#[derive(Debug, Clone, Default)]
+pub struct Notifier {
+    pub webhooks: Vec<Webhook>,
+    pub timeout:  u64,
+    pub retries:  u64,
+}
+
+/// Read the universe's top-level `notifications` block, adding any webhooks
+/// from the application's own `notifications` block.
+pub fn notifier(u: &Yaml, app: &str) -> Notifier {
+    let n = &u["notifications"];
+    let mut webhooks = parse_webhooks(&n["webhooks"]);
+    webhooks.extend(parse_webhooks(&u["applications"][app]["notifications"]["webhooks"]));
+
+    Notifier {
+        webhooks,
+        timeout: n["timeout"].as_i64().unwrap_or(5) as u64,
+        retries: n["retries"].as_i64().unwrap_or(2) as u64,
+    }
+}
+
+fn parse_webhooks(y: &Yaml) -> Vec<Webhook> {
+    let mut webhooks = Vec::new();
+    for w in y.as_vec().cloned().unwrap_or_default() {
+        let url = match w["url"].as_str() {
+            Some(url) => url.to_string(),
+            None => panic!("ERROR: notification webhooks must have a url"),
+        };
+        let format = w["format"].as_str().unwrap_or("json").to_string();
+        if !FORMATS.contains(&format.as_str()) {
+            panic!("ERROR: unknown webhook format {} for {} (available: {})", format, url, FORMATS.join(", "));
+        };
+        let events: Vec<String> = w["events"].as_vec().cloned().unwrap_or_default().iter()
+            .filter_map(|e| e.as_str().map(|s| s.to_string()))
+            .collect();
+        if let Some(e) = events.iter().find(|e| !EVENTS.contains(&e.as_str())) {
+            panic!("ERROR: unknown notification event {} for {} (available: {})", e, url, EVENTS.join(", "));
+        };
+        webhooks.push(Webhook { url, format, events });
+    };
+    webhooks
+}
+
+/// Send `event` to every webhook subscribed to it.  Delivery failures are
+/// logged and otherwise ignored.  Nothing is sent in dry runs.
+pub fn notify(c: &DeploymentContext, event: &str, message: &str) {
+    if dry_run() {
+        return
+    };
+
+    for w in &c.notifier.webhooks {
+        if !w.events.is_empty() && !w.events.iter().any(|e| e == event) {
+            continue
+        };
+
+        let payload = match w.format.as_str() {
+            "slack" => slack_payload(c, event, message),
+            "teams" => teams_payload(c, event, message),
+            _ => json_payload(c, event, message),
+        };
+        send(&c.notifier, &w.url, &payload);
+    };
+}
+
+fn send(n: &Notifier, url: &str, payload: &Value) {
+    let client = match reqwest::Client::builder().timeout(time::Duration::from_secs(n.timeout)).build() {
+        Ok(client) => client,
+        Err(error) => {
+            warn!("WARN: could not create webhook client: {:?}", error);
+            return
+        },
+    };
+
+    for attempt in 0..=n.retries {
+        if attempt > 0 {
+            thread::sleep(time::Duration::from_secs(attempt));
+        };
+
+        match client.post(url).json(payload).send() {
+            Ok(ref res) if res.status().is_success() => {
+                debug!("notification delivered to {}", url);
+                return
+            },
+            Ok(res) => warn!("WARN: webhook {} returned {} (attempt {} of {})", url, res.status(), attempt + 1, n.retries + 1),
+            Err(error) => warn!("WARN: webhook {} failed: {} (attempt {} of {})", url, error, attempt + 1, n.retries + 1),
+        };
+    };
+
+    warn!("WARN: giving up on webhook {}", url);
+}
+
+fn summary(c: &DeploymentContext, message: &str) -> String {
+    format!("{} {} in {} ({}): {}", c.deploy.application, c.deploy.environment, c.region.name(), c.deploy.version, message)
+}
+
+fn json_payload(c: &DeploymentContext, event: &str, message: &str) -> Value {
+    json!({
+        "event": event,
+        "message": message,
+        "app": c.deploy.application,
+        "env": c.deploy.environment,
+        "region": c.region.name(),
+        "version": c.deploy.version,
+        "previous_version": c.green_asg_info.version,
+        "launch_config": c.lc_name,
+        "strategy": c.deploy.strategy,
+        "canary_stats": *c.canary_stats.borrow(),
+    })
+}
+
+fn slack_payload(c: &DeploymentContext, event: &str, message: &str) -> Value {
+    json!({
+        "text": format!("*burnish* {}", summary(c, message)),
+        "attachments": [{
+            "color": format!("#{}", color(event)),
+            "fields": [
+                { "title": "Event", "value": event, "short": true },
+                { "title": "Previous version", "value": c.green_asg_info.version, "short": true },
+            ],
+        }],
+    })
+}
+
+fn teams_payload(c: &DeploymentContext, event: &str, message: &str) -> Value {
+    json!({
+        "@type": "MessageCard",
+        "@context": "https://schema.org/extensions",
+        "summary": summary(c, message),
+        "themeColor": color(event),
+        "title": format!("burnish: {} {}", c.deploy.application, event),
+        "text": summary(c, message),
+    })
+}
+
+fn color(event: &str) -> &str {
+    match event {
+        "canary_failed" | "failed" => "d63333",
+        "rolled_back" => "e8a33d",
+        "canary_passed" | "completed" => "2eb886",
+        _ => "439fe0",
+    }
+}
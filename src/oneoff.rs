@@ -1,6 +1,7 @@
 use rusoto_ec2::{Ec2, Ec2Client, Tag, TagSpecification,
     IamInstanceProfileSpecification, RunInstancesRequest,
     TerminateInstancesRequest};
+use utils::skip_for_dry_run;
 
 pub fn launch_instance(m: &clap::ArgMatches, r: rusoto_core::Region) {
     let ec2_client = Ec2Client::new(r.to_owned());
@@ -29,6 +30,10 @@ pub fn launch_instance(m: &clap::ArgMatches, r: rusoto_core::Region) {
         tag_specifications: Some(vec![tag_spec]),
         ..Default::default()
     };
+    if skip_for_dry_run("ec2", "RunInstances", format!("{} (ami: {})", m.value_of("name").unwrap(), m.value_of("ami").unwrap())) {
+        return
+    };
+
     match ec2_client.run_instances(run_req).sync() {
         Ok(_a) => println!("SUCCESS: Instance successfully launched"),
         Err(error) => eprintln!("ERROR: {:?}", error),
@@ -41,6 +46,10 @@ pub fn term_instances(m: &clap::ArgMatches, r: rusoto_core::Region) {
         instance_ids: m.value_of("instanceids").unwrap().split(",").map(|s| s.to_string()).collect(),
         ..Default::default()
     };
+    if skip_for_dry_run("ec2", "TerminateInstances", term_req.instance_ids.join(", ")) {
+        return
+    };
+
     match ec2_client.terminate_instances(term_req).sync() {
         Ok(_a) => println!("SUCCESS: Instance(s) successfully terminated"),
         Err(error) => eprintln!("ERROR: {:#?}", error),
@@ -0,0 +1,160 @@
+use std::{thread, time};
+use deployment::DeploymentContext;
+use autoscalegroup::list_asg;
+use utils::{describe_instances, dry_run};
+
+/// How to check an application's own health and version endpoints, from the
+/// application's `probe` block in the universe.  Probing is off unless at
+/// least one path is set.
+#[derive(Debug, Clone, Default)]
+pub struct Probe {
+    pub scheme:       std::string::String,
+    pub port:         i64,
+    pub health_path:  Option<std::string::String>,
+    pub version_path: Option<std::string::String>,
+    /// Seconds to keep retrying an instance that is not healthy yet.
+    pub timeout:      u64,
+}
+
+impl Probe {
+    pub fn enabled(&self) -> bool {
+        self.health_path.is_some() || self.version_path.is_some()
+    }
+
+    fn url(&self, ip: &str, path: &str) -> String {
+        format!("{}://{}:{}{}", self.scheme, ip, self.port, path)
+    }
+}
+
+pub fn probe_config(u: &yaml_rust::Yaml, app: &str) -> Probe {
+    let p = &u["applications"][app]["probe"];
+    Probe {
+        scheme:       p["scheme"].as_str().unwrap_or("http").to_string(),
+        port:         p["port"].as_i64().unwrap_or(80),
+        health_path:  p["health_path"].as_str().map(|s| s.to_string()),
+        version_path: p["version_path"].as_str().map(|s| s.to_string()),
+        timeout:      p["timeout"].as_i64().unwrap_or(120) as u64,
+    }
+}
+
+/// Probe the InService instances of `asg` that were launched from the new
+/// launch configuration.
+pub fn probe_new_instances(c: &DeploymentContext, asg: &str) -> Result<(), String> {
+    if !c.probe.enabled() || dry_run() {
+        return Ok(())
+    };
+
+    let asgs = list_asg(c.region.clone(), asg.to_string());
+    let ids: Vec<String> = asgs.iter()
+        .flat_map(|a| a.instances.iter())
+        .filter(|i| i.lifecycle_state == "InService" && i.launch_configuration_name.as_ref() == Some(&c.lc_name))
+        .map(|i| i.instance_id.clone())
+        .collect();
+
+    probe_instances(c, &ids)
+}
+
+/// Check that every instance in `ids` reports healthy and is serving the
+/// version being deployed.  Unhealthy instances are retried until the probe
+/// timeout; an instance serving the wrong version fails at once.
+pub fn probe_instances(c: &DeploymentContext, ids: &[String]) -> Result<(), String> {
+    if !c.probe.enabled() || ids.is_empty() || dry_run() {
+        return Ok(())
+    };
+
+    let client = reqwest::Client::builder()
+        .timeout(time::Duration::from_secs(10))
+        .build()
+        .map_err(|e| format!("{:?}", e))?;
+
+    for (id, ip) in private_ips(c.region.clone(), ids) {
+        let started = time::Instant::now();
+        loop {
+            match probe_instance(&client, &c.probe, &ip, &c.deploy.version) {
+                Ok(()) => {
+                    info!("{} ({}) is healthy and serving {}", id, ip, c.deploy.version);
+                    break
+                },
+                Err(Failure::WrongVersion(v)) => {
+                    return Err(format!("{} ({}) is serving version {}, expected {}", id, ip, v, c.deploy.version))
+                },
+                Err(Failure::Unhealthy(e)) => {
+                    if started.elapsed().as_secs() >= c.probe.timeout {
+                        return Err(format!("{} ({}) did not become healthy within {} seconds: {}", id, ip, c.probe.timeout, e))
+                    };
+                    info!("waiting for {} ({}) to become healthy: {}", id, ip, e);
+                    thread::sleep(time::Duration::from_secs(5));
+                },
+            };
+        };
+    };
+
+    Ok(())
+}
+
+enum Failure {
+    Unhealthy(String),
+    WrongVersion(String),
+}
+
+fn probe_instance(client: &reqwest::Client, p: &Probe, ip: &str, version: &str) -> Result<(), Failure> {
+    if let Some(ref path) = p.health_path {
+        get(client, &p.url(ip, path)).map_err(Failure::Unhealthy)?;
+    };
+
+    if let Some(ref path) = p.version_path {
+        let body = get(client, &p.url(ip, path)).map_err(Failure::Unhealthy)?;
+        let reported = reported_version(&body);
+        if reported != version {
+            return Err(Failure::WrongVersion(reported))
+        };
+    };
+
+    Ok(())
+}
+
+fn get(client: &reqwest::Client, url: &str) -> Result<String, String> {
+    match client.get(url).send() {
+        Ok(mut res) => {
+            if !res.status().is_success() {
+                return Err(format!("{} returned {}", url, res.status()))
+            };
+            res.text().map_err(|e| format!("{}: {}", url, e))
+        },
+        Err(error) => Err(format!("{}: {}", url, error)),
+    }
+}
+
+/// The version in a version endpoint's response: either the whole body, or
+/// the `version` field of a JSON object.
+pub fn reported_version(body: &str) -> String {
+    match serde_json::from_str::<serde_json::Value>(body) {
+        Ok(ref v) if v["version"].is_string() => v["version"].as_str().unwrap().to_string(),
+        _ => body.trim().to_string(),
+    }
+}
+
+fn private_ips(r: rusoto_core::Region, ids: &[String]) -> Vec<(String, String)> {
+    describe_instances(r, ids).into_iter()
+        .filter_map(|i| match (i.instance_id, i.private_ip_address) {
+            (Some(id), Some(ip)) => Some((id, ip)),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Plan lines for probing the new instances of `asg`.
+pub fn probe_calls(c: &DeploymentContext, asg: &str) -> Vec<String> {
+    let mut calls = Vec::new();
+    if !c.probe.enabled() {
+        return calls
+    };
+
+    for path in c.probe.health_path.iter() {
+        calls.push(format!("probe {} on new instances in {} until healthy (up to {} seconds)", c.probe.url("<private-ip>", path), asg, c.probe.timeout));
+    };
+    for path in c.probe.version_path.iter() {
+        calls.push(format!("probe {} on new instances in {} and fail unless it reports {}", c.probe.url("<private-ip>", path), asg, c.deploy.version));
+    };
+    calls
+}
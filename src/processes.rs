@@ -0,0 +1,306 @@
+use chrono::{DateTime, Duration, SecondsFormat, Utc};
+use rusoto_autoscaling::{Autoscaling, AutoscalingClient, CreateOrUpdateTagsType, DeleteTagsType, ScalingProcessQuery, Tag};
+use autoscalegroup::{AutoScaleGroup, asg_record, list_asg};
+use audit::audited;
+use utils::{aws_call, skip_for_dry_run};
+
+/// Every scaling process an ASG can suspend.
+pub const SCALING_PROCESSES: &[&str] = &[
+    "Launch", "Terminate", "AddToLoadBalancer", "AlarmNotification", "AZRebalance",
+    "HealthCheck", "InstanceRefresh", "ReplaceUnhealthy", "ScheduledActions",
+];
+
+/// Tag recording when a suspension made with `--for` runs out.
+const UNTIL_TAG: &str = "burnish:suspended-until";
+
+/// Tag recording which processes to resume when the suspension runs out.
+const PROCESSES_TAG: &str = "burnish:suspended-processes";
+
+/// Suspend `processes` of `n`.  With `until`, the suspension is recorded on
+/// the group so that `resume --expired` resumes the processes once it
+/// passes, along with any others already waiting to be; without it, they
+/// stay suspended until resumed by hand.
+pub fn suspend_processes(r: rusoto_core::Region, n: &str, processes: &[String], until: Option<DateTime<Utc>>) {
+    let expiry = until.map(|u| format!(" until {}", u.to_rfc3339_opts(SecondsFormat::Secs, true))).unwrap_or_default();
+    if skip_for_dry_run("autoscaling", "SuspendProcesses", format!("{} ({}){}", n, processes.join(", "), expiry)) {
+        return
+    };
+
+    let as_client = AutoscalingClient::new(r.to_owned());
+    let req = ScalingProcessQuery {
+        auto_scaling_group_name: n.to_string(),
+        scaling_processes: Some(processes.to_vec()),
+    };
+    match as_client.suspend_processes(req).sync() {
+        Ok(_k) => info!("suspended {} processes for {}{}", processes.join(" "), n, expiry),
+        Err(error) => panic!("ERROR: {:?}", error),
+    };
+
+    let u = match until {
+        Some(u) => u,
+        None => return forget_expiry(&as_client, r, n, processes),
+    };
+
+    let mut expiring = list_asg(r, n.to_string()).into_iter().next().map(|a| expiring_processes(&a)).unwrap_or_default();
+    for p in processes {
+        if !expiring.contains(p) {
+            expiring.push(p.clone());
+        };
+    };
+    let tags = vec![
+        suspension_tag(n, UNTIL_TAG, &u.to_rfc3339_opts(SecondsFormat::Secs, true)),
+        suspension_tag(n, PROCESSES_TAG, &expiring.join(",")),
+    ];
+    if let Err(error) = as_client.create_or_update_tags(CreateOrUpdateTagsType { tags }).sync() {
+        panic!("ERROR: {:?}", error);
+    };
+}
+
+/// Resume `processes` of `n`.  Any of them waiting on an expiry are no
+/// longer tracked, and the expiry is removed once none are left.
+pub fn resume_processes(r: rusoto_core::Region, n: &str, processes: &[String]) {
+    if skip_for_dry_run("autoscaling", "ResumeProcesses", format!("{} ({})", n, processes.join(", "))) {
+        return
+    };
+
+    let as_client = AutoscalingClient::new(r.to_owned());
+    // An empty list would resume every process.
+    if !processes.is_empty() {
+        let req = ScalingProcessQuery {
+            auto_scaling_group_name: n.to_string(),
+            scaling_processes: Some(processes.to_vec()),
+        };
+        match as_client.resume_processes(req).sync() {
+            Ok(_k) => info!("resumed {} processes for {}", processes.join(" "), n),
+            Err(error) => panic!("ERROR: {:?}", error),
+        };
+    };
+
+    forget_expiry(&as_client, r, n, processes);
+}
+
+/// Stop tracking an expiry for `processes` of `n`, removing the expiry once
+/// no processes are left waiting on it.
+fn forget_expiry(as_client: &AutoscalingClient, r: rusoto_core::Region, n: &str, processes: &[String]) {
+    let asg = match list_asg(r, n.to_string()).into_iter().next() {
+        Some(a) => a,
+        None => return,
+    };
+    let pending: Vec<String> = expiring_processes(&asg).into_iter().filter(|p| !processes.contains(p)).collect();
+    if asg.tag(UNTIL_TAG).is_none() && asg.tag(PROCESSES_TAG).is_none() {
+        return
+    };
+    let result = if pending.is_empty() {
+        let tags = vec![suspension_tag(n, UNTIL_TAG, ""), suspension_tag(n, PROCESSES_TAG, "")];
+        as_client.delete_tags(DeleteTagsType { tags }).sync().map_err(|e| format!("{:?}", e))
+    } else {
+        let tags = vec![suspension_tag(n, PROCESSES_TAG, &pending.join(","))];
+        as_client.create_or_update_tags(CreateOrUpdateTagsType { tags }).sync().map_err(|e| format!("{:?}", e))
+    };
+    if let Err(error) = result {
+        panic!("ERROR: {}", error);
+    };
+}
+
+/// Whether the suspension recorded on `asg` has run out, so that its
+/// processes are waiting for `resume --expired`.
+pub fn suspension_expired(asg: &AutoScaleGroup) -> bool {
+    suspended_until(asg).map(|u| u <= Utc::now()).unwrap_or(false)
+}
+
+/// `asg` as it is once its suspension has been resumed, if it has run out:
+/// without the expired processes or the tags recording the expiry.
+pub fn without_expired_suspension(asg: &AutoScaleGroup) -> AutoScaleGroup {
+    let mut asg = asg.clone();
+    if suspension_expired(&asg) {
+        let expiring = expiring_processes(&asg);
+        asg.suspended_processes.retain(|p| !expiring.contains(p));
+        asg.tags.retain(|t| t.key != UNTIL_TAG && t.key != PROCESSES_TAG);
+    };
+    asg
+}
+
+/// The call `resume_expired_suspension` makes for `asg`, if any.
+pub fn resume_expired_calls(asg: &AutoScaleGroup) -> Vec<String> {
+    if !suspension_expired(asg) {
+        return Vec::new()
+    };
+    vec![aws_call("autoscaling", "ResumeProcesses", format!("{} ({}, suspension expired)", asg.name, expiring_processes(asg).join(", ")))]
+}
+
+/// Resume the processes of `asg` if its suspension has run out, so that a
+/// command about to change the group does not have to wait for
+/// `resume --expired` to run.  Returns the group as it is afterwards.
+pub fn resume_expired_suspension(r: rusoto_core::Region, asg: &AutoScaleGroup) -> AutoScaleGroup {
+    if suspension_expired(asg) {
+        let processes = expiring_processes(asg);
+        let until = suspended_until(asg).map(|u| u.to_rfc3339_opts(SecondsFormat::Secs, true)).unwrap_or_default();
+        info!("the suspension of {} processes for {} ran out at {}, resuming them", processes.join(" "), asg.name, until);
+        resume_processes(r, &asg.name, &processes);
+    };
+    without_expired_suspension(asg)
+}
+
+/// When the suspension recorded on `asg` runs out, if there is one.
+pub fn suspended_until(asg: &AutoScaleGroup) -> Option<DateTime<Utc>> {
+    asg.tag(UNTIL_TAG)
+        .and_then(|u| DateTime::parse_from_rfc3339(u).ok())
+        .map(|u| u.with_timezone(&Utc))
+}
+
+/// The processes of `asg` that are resumed when its suspension runs out.
+pub fn expiring_processes(asg: &AutoScaleGroup) -> Vec<String> {
+    asg.tag(PROCESSES_TAG).unwrap_or("").split(',')
+        .filter(|p| !p.is_empty())
+        .map(|p| p.to_string())
+        .collect()
+}
+
+/// The suspended processes of `asg` for `list` and `show`, each marked with
+/// when it will be resumed if it has an expiry, or that the expiry has
+/// passed and it is waiting for `resume --expired`.
+pub fn suspension_description(asg: &AutoScaleGroup) -> String {
+    let expiring = expiring_processes(asg);
+    let expired = suspension_expired(asg);
+    let until = suspended_until(asg).map(|u| u.to_rfc3339_opts(SecondsFormat::Secs, true));
+    asg.suspended_processes.iter().map(|p| match until {
+        Some(ref u) if expiring.contains(p) && expired => format!("{} (expired {})", p, u),
+        Some(ref u) if expiring.contains(p) => format!("{} (until {})", p, u),
+        _ => p.clone(),
+    }).collect::<Vec<_>>().join(", ")
+}
+
+fn suspension_tag(n: &str, key: &str, value: &str) -> Tag {
+    Tag {
+        key:                 key.to_string(),
+        propagate_at_launch: Some(false),
+        resource_id:         Some(n.to_string()),
+        resource_type:       Some("auto-scaling-group".to_string()),
+        value:               Some(value.to_string()),
+    }
+}
+
+/// The processes named by `--processes`, or every process if it was not
+/// given.
+fn selected_processes(m: &clap::ArgMatches) -> Vec<String> {
+    let processes: Vec<String> = match m.values_of("processes") {
+        Some(v) => v.map(|p| p.to_string()).collect(),
+        None => SCALING_PROCESSES.iter().map(|p| p.to_string()).collect(),
+    };
+    if let Some(p) = processes.iter().find(|p| !SCALING_PROCESSES.contains(&p.as_str())) {
+        panic!("ERROR: unknown scaling process {} (available: {})", p, SCALING_PROCESSES.join(", "));
+    };
+    processes
+}
+
+pub fn suspend_processes_cmd(r: rusoto_core::Region, m: &clap::ArgMatches, u: yaml_rust::Yaml) {
+    let name = m.value_of("name").unwrap().to_string();
+    let processes = selected_processes(m);
+    let until = m.value_of("for").map(|f| Utc::now() + Duration::minutes(f.parse::<i64>().unwrap()));
+
+    audited(r.clone(), &u, asg_record("suspend", r.clone(), &name), |a| {
+        a.detail = format!("{} {}", name, processes.join(", "));
+        if let Some(u) = until {
+            a.detail = format!("{} until {}", a.detail, u.to_rfc3339_opts(SecondsFormat::Secs, true));
+        };
+        suspend_processes(r.clone(), &name, &processes, until);
+    });
+}
+
+pub fn resume_processes_cmd(r: rusoto_core::Region, m: &clap::ArgMatches, u: yaml_rust::Yaml) {
+    if m.is_present("expired") {
+        resume_expired_cmd(r, m, u);
+        return
+    };
+
+    let name = m.value_of("name").unwrap().to_string();
+    let processes = selected_processes(m);
+
+    audited(r.clone(), &u, asg_record("resume", r.clone(), &name), |a| {
+        a.detail = format!("{} {}", name, processes.join(", "));
+        resume_processes(r.clone(), &name, &processes);
+    });
+}
+
+/// Resume the processes of every ASG, or just `--name`, whose suspension
+/// has run out.  `burnish` has no daemon, so this is meant to be run from
+/// cron.
+fn resume_expired_cmd(r: rusoto_core::Region, m: &clap::ArgMatches, u: yaml_rust::Yaml) {
+    let expired: Vec<AutoScaleGroup> = list_asg(r.clone(), m.value_of("name").unwrap_or("").to_string()).into_iter()
+        .filter(suspension_expired)
+        .collect();
+    if expired.is_empty() {
+        info!("no suspensions have run out");
+        return
+    };
+
+    for asg in expired {
+        let processes = expiring_processes(&asg);
+        let until = suspended_until(&asg).map(|u| u.to_rfc3339_opts(SecondsFormat::Secs, true)).unwrap_or_default();
+        audited(r.clone(), &u, asg_record("resume expired", r.clone(), &asg.name), |a| {
+            a.detail = format!("{} {} expired {}", asg.name, processes.join(", "), until);
+            info!("the suspension of {} processes for {} ran out at {}, resuming them", processes.join(" "), asg.name, until);
+            resume_processes(r.clone(), &asg.name, &processes);
+        });
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tag(key: &str, value: &str) -> Tag {
+        suspension_tag("asg", key, value)
+    }
+
+    fn group(until: &str) -> AutoScaleGroup {
+        AutoScaleGroup {
+            name:                "asg".to_string(),
+            min_size:            1,
+            max_size:            2,
+            desired_capacity:    1,
+            instance_count:      1,
+            lc_name:             "lc".to_string(),
+            launch_template:     None,
+            version:             String::new(),
+            instances:           Vec::new(),
+            elbs:                Vec::new(),
+            suspended_processes: vec!["AZRebalance".to_string(), "Launch".to_string(), "Terminate".to_string()],
+            availability_zones:  Vec::new(),
+            subnets:             Vec::new(),
+            tags:                vec![tag("app", "web"), tag(UNTIL_TAG, until), tag(PROCESSES_TAG, "Launch,Terminate")],
+            app_name:            String::new(),
+            env_name:            String::new(),
+        }
+    }
+
+    fn from_now(minutes: i64) -> String {
+        (Utc::now() + Duration::minutes(minutes)).to_rfc3339_opts(SecondsFormat::Secs, true)
+    }
+
+    #[test]
+    fn an_expired_suspension_is_dropped_with_its_tags() {
+        let asg = without_expired_suspension(&group(&from_now(-5)));
+        assert_eq!(asg.suspended_processes, vec!["AZRebalance"]);
+        assert_eq!(asg.tags.iter().map(|t| t.key.as_str()).collect::<Vec<_>>(), vec!["app"]);
+        assert_eq!(resume_expired_calls(&group(&from_now(-5))),
+                   vec!["autoscaling:ResumeProcesses asg (Launch, Terminate, suspension expired)"]);
+    }
+
+    #[test]
+    fn a_running_suspension_is_kept() {
+        let asg = group(&from_now(5));
+        assert_eq!(without_expired_suspension(&asg).suspended_processes, asg.suspended_processes);
+        assert!(resume_expired_calls(&asg).is_empty());
+    }
+
+    #[test]
+    fn descriptions_mark_expiring_processes() {
+        let until = from_now(5);
+        assert_eq!(suspension_description(&group(&until)),
+                   format!("AZRebalance, Launch (until {}), Terminate (until {})", until, until));
+        let until = from_now(-5);
+        assert_eq!(suspension_description(&group(&until)),
+                   format!("AZRebalance, Launch (expired {}), Terminate (expired {})", until, until));
+    }
+}
@@ -0,0 +1,135 @@
+use std::{thread, time};
+use chrono::{DateTime, Duration as ChronoDuration, Utc};
+use yaml_rust::Yaml;
+use audit::{audit_store, read_records};
+use manifest::Settings;
+use regions::deploy_regions;
+use strategy::StrategyRegistry;
+use utils::{confirm, dry_run};
+
+/// One environment in a promotion pipeline.
+#[derive(Debug, Clone, Default)]
+pub struct Stage {
+    pub env:      std::string::String,
+    /// Minutes a version must run here before it is promoted to the next stage.
+    pub soak:     u64,
+    /// Ask for approval before deploying to this stage.
+    pub approval: bool,
+}
+
+/// The stages of the pipeline named `name`, or of the application's
+/// `pipeline` if no name is given.
+pub fn pipeline(u: &Yaml, app: &str, name: Option<&str>) -> (String, Vec<Stage>) {
+    let name = match name.or_else(|| u["applications"][app]["pipeline"].as_str()) {
+        Some(n) => n.to_string(),
+        None => panic!("ERROR: {} has no pipeline in this universe; pass --pipeline", app),
+    };
+
+    let stages = match u["pipelines"][name.as_str()]["stages"].as_vec() {
+        Some(s) if !s.is_empty() => s.clone(),
+        _ => panic!("ERROR: pipeline {} has no stages in this universe", name),
+    };
+
+    let stages = stages.iter().map(|s| {
+        let env = match s["env"].as_str() {
+            Some(e) => e.to_string(),
+            None => panic!("ERROR: every stage of pipeline {} needs an env", name),
+        };
+        if u["environments"][env.as_str()].is_badvalue() {
+            panic!("ERROR: pipeline {} stage {} does not exist in this universe.", name, env);
+        };
+        Stage {
+            env,
+            soak:     s["soak"].as_i64().unwrap_or(0) as u64,
+            approval: s["approval"].as_bool().unwrap_or(false),
+        }
+    }).collect();
+
+    (name, stages)
+}
+
+/// Check the audit history of `stage` for `version`: it must be the version
+/// most recently deployed there, and that deployment must have succeeded.
+/// Returns how many seconds of the stage's soak time are left.
+pub fn promotable(r: rusoto_core::Region, u: &Yaml, app: &str, version: &str, stage: &Stage) -> Result<u64, String> {
+    let records = read_records(r, &audit_store(u), app, &stage.env);
+    let last = records.iter().rev()
+        .find(|a| (a.action == "deploy" || a.action == "rollback") && a.result == "success");
+
+    let last = match last {
+        Some(a) if a.version == version => a,
+        Some(a) => return Err(format!("{} is running {}, not {}", stage.env, a.version, version)),
+        None => return Err(format!("{} has never been deployed successfully to {}", version, stage.env)),
+    };
+
+    let finished = match DateTime::parse_from_rfc3339(&last.started_at) {
+        Ok(t) => t.with_timezone(&Utc) + ChronoDuration::seconds(last.duration_secs as i64),
+        Err(_) => return Err(format!("the deployment record for {} in {} has no valid start time", version, stage.env)),
+    };
+    let soaked = Utc::now().signed_duration_since(finished).num_seconds().max(0) as u64;
+
+    Ok((stage.soak * 60).saturating_sub(soaked))
+}
+
+pub fn promote_cmd(r: rusoto_core::Region, m: &clap::ArgMatches, u: Yaml) {
+    let app = m.value_of("app").unwrap();
+    let version = m.value_of("version").unwrap();
+    let (name, stages) = pipeline(&u, app, m.value_of("pipeline"));
+
+    let stage_index = |flag: &str, default: usize| match m.value_of(flag) {
+        None => default,
+        Some(env) => match stages.iter().position(|s| s.env == env) {
+            Some(i) => i,
+            None => panic!("ERROR: {} is not a stage of pipeline {}", env, name),
+        },
+    };
+    let from = stage_index("from", 0);
+    let to = stage_index("to", stages.len() - 1);
+    if from > to {
+        panic!("ERROR: --from {} comes after --to {} in pipeline {}", stages[from].env, stages[to].env, name);
+    };
+
+    let registry = StrategyRegistry::default();
+    for i in from..=to {
+        let stage = &stages[i];
+        info!("promoting {} {} to {} (stage {} of {} in pipeline {})", app, version, stage.env, i + 1, stages.len(), name);
+
+        if i > 0 {
+            let prev = &stages[i - 1];
+            if dry_run() && i > from {
+                println!("[dry-run] check {} succeeded in {} and wait out its {} minute soak", version, prev.env, prev.soak);
+            } else {
+                match promotable(r.clone(), &u, app, version, prev) {
+                    Ok(0) => (),
+                    Ok(wait) if dry_run() => println!("[dry-run] wait {} more seconds for {} to soak in {}", wait, version, prev.env),
+                    Ok(wait) => {
+                        info!("waiting {} more seconds for {} to soak in {}", wait, version, prev.env);
+                        thread::sleep(time::Duration::from_secs(wait));
+                        promotable(r.clone(), &u, app, version, prev).unwrap_or_else(|e| panic!("ERROR: cannot promote to {}: {}", stage.env, e));
+                    },
+                    Err(e) => panic!("ERROR: cannot promote to {}: {}", stage.env, e),
+                };
+            };
+        };
+
+        if stage.approval {
+            if dry_run() {
+                println!("[dry-run] ask for approval to deploy {} to {}", version, stage.env);
+            } else if !m.is_present("yes") && !confirm(&format!("Deploy {} {} to {}?", app, version, stage.env)) {
+                panic!("ERROR: promotion of {} to {} was not approved", version, stage.env);
+            };
+        };
+
+        let mut overrides = Settings::new();
+        overrides.insert("app".to_string(), Yaml::String(app.to_string()));
+        overrides.insert("env".to_string(), Yaml::String(stage.env.clone()));
+        overrides.insert("version".to_string(), Yaml::String(version.to_string()));
+
+        deploy_regions(r.clone(), m, &u, &registry, overrides);
+        if dry_run() {
+            println!();
+        };
+    };
+
+    info!("promotion of {} {} through {} is complete", app, version, stages[to].env);
+}
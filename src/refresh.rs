@@ -0,0 +1,367 @@
+use std::cmp;
+use std::collections::BTreeMap;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+use futures::Future;
+use rusoto_core::Client;
+use rusoto_core::param::{Params, ServiceParams};
+use rusoto_core::signature::SignedRequest;
+use xml::reader::{EventReader, XmlEvent};
+use autoscalegroup::{AutoScaleGroup, RotationPlan};
+use utils::aws_call;
+
+/// Seconds a new instance is given to warm up before it counts towards the
+/// group's healthy percentage during an instance refresh.
+pub const REFRESH_WARMUP: i64 = 300;
+
+/// Seconds an instance refresh may take per batch before it is cancelled.
+const REFRESH_BATCH_TIMEOUT: u64 = 60 * 15;
+
+/// Instance refresh states after which the refresh does nothing more.
+const DONE_STATES: &[&str] = &["Successful", "Failed", "Cancelled", "RollbackSuccessful", "RollbackFailed"];
+
+/// The state of one of an ASG's instance refreshes, from
+/// `DescribeInstanceRefreshes`.
+#[derive(Debug, Clone, Default)]
+pub struct InstanceRefresh {
+    pub id:                  std::string::String,
+    pub status:              std::string::String,
+    pub status_reason:       std::string::String,
+    pub percentage_complete: i64,
+    pub instances_to_update: i64,
+}
+
+/// The minimum and maximum healthy percentages that let an instance refresh
+/// take `plan.unavailable` instances below, and add `plan.surge` above, the
+/// group's desired capacity, as a rotation would.  AWS allows at most 100
+/// points between the two, so the surge gives way if need be.
+pub fn healthy_percentages(plan: &RotationPlan) -> (i64, i64) {
+    if plan.desired <= 0 {
+        return (100, 100)
+    };
+    let min = (100 - plan.unavailable as i64 * 100 / plan.desired).clamp(0, 100);
+    let max = (100 + (plan.surge as i64 * 100 + plan.desired - 1) / plan.desired).clamp(100, min + 100);
+    (min, max)
+}
+
+/// Replace the instances in `plan` with the group's native instance refresh
+/// rather than batch by batch.  `after_batch` is called as the refresh's
+/// progress passes each of the plan's batches; an `Err` from it, a failed
+/// refresh or a timeout cancels the refresh.
+pub fn refresh_instances<F>(r: &rusoto_core::Region, asg: &AutoScaleGroup, plan: &RotationPlan, after_batch: &mut F) -> Result<(), String>
+    where F: FnMut(usize, usize) -> Result<(), String>
+{
+    let (min_healthy, max_healthy) = healthy_percentages(plan);
+    let mut params = vec![
+        ("AutoScalingGroupName", asg.name.clone()),
+        ("Strategy", "Rolling".to_string()),
+        ("Preferences.MinHealthyPercentage", min_healthy.to_string()),
+        ("Preferences.MaxHealthyPercentage", max_healthy.to_string()),
+        ("Preferences.InstanceWarmup", REFRESH_WARMUP.to_string()),
+    ];
+    if asg.launch_template.is_some() {
+        params.push(("Preferences.SkipMatching", "true".to_string()));
+    } else {
+        info!("{} uses a launch configuration, so its instance refresh replaces every instance", asg.name);
+    };
+
+    let id = match autoscaling_call(r, "StartInstanceRefresh", &params) {
+        Ok(body) => first_element(&body, "InstanceRefreshId").unwrap_or_default(),
+        Err(e) => return Err(format!("could not start an instance refresh of {}: {}", asg.name, e)),
+    };
+    info!("started instance refresh {} of {} (healthy: {}%-{}%, warmup: {}s)", id, asg.name, min_healthy, max_healthy, REFRESH_WARMUP);
+
+    let total = plan.batches.len();
+    let mut done = 0;
+    let now = Instant::now();
+    let timeout = REFRESH_BATCH_TIMEOUT * cmp::max(total, 1) as u64;
+
+    loop {
+        sleep(Duration::from_secs(15));
+        let refresh = match describe_instance_refresh(r, &asg.name, &id) {
+            Some(refresh) => refresh,
+            None => return Err(format!("instance refresh {} of {} could not be found", id, asg.name)),
+        };
+        info!("instance refresh {} of {}: {} ({}% complete, {} instance(s) left)",
+            id, asg.name, refresh.status, refresh.percentage_complete, refresh.instances_to_update);
+
+        let finished = refresh.status == "Successful";
+        if DONE_STATES.contains(&refresh.status.as_str()) && !finished {
+            return Err(format!("instance refresh {} of {} ended {}: {}", id, asg.name, refresh.status, refresh.status_reason))
+        };
+
+        let reached = if finished { total } else { refresh.percentage_complete as usize * total / 100 };
+        while done < reached {
+            done += 1;
+            if let Err(e) = after_batch(done, total) {
+                cancel_instance_refresh(r, &asg.name);
+                return Err(e)
+            };
+        };
+
+        if finished {
+            info!("instance refresh {} of {} is complete", id, asg.name);
+            return Ok(())
+        };
+        if now.elapsed().as_secs() > timeout {
+            cancel_instance_refresh(r, &asg.name);
+            return Err(format!("timed out after {}s waiting for instance refresh {} of {}", timeout, id, asg.name))
+        };
+    };
+}
+
+/// The instance refresh of `n` with ID `id`.
+pub fn describe_instance_refresh(r: &rusoto_core::Region, n: &str, id: &str) -> Option<InstanceRefresh> {
+    let params = [
+        ("AutoScalingGroupName", n.to_string()),
+        ("InstanceRefreshIds.member.1", id.to_string()),
+    ];
+    let body = match autoscaling_call(r, "DescribeInstanceRefreshes", &params) {
+        Ok(body) => body,
+        Err(e) => panic!("ERROR: {}", e),
+    };
+
+    instance_refreshes(&body).into_iter().next()
+}
+
+/// The instance refreshes in a `DescribeInstanceRefreshes` response.
+fn instance_refreshes(body: &[u8]) -> Vec<InstanceRefresh> {
+    list_members(body, "InstanceRefreshes").into_iter().map(|m| InstanceRefresh {
+        id:                  m.get("InstanceRefreshId").cloned().unwrap_or_default(),
+        status:              m.get("Status").cloned().unwrap_or_default(),
+        status_reason:       m.get("StatusReason").cloned().unwrap_or_default(),
+        percentage_complete: m.get("PercentageComplete").and_then(|p| p.parse().ok()).unwrap_or(0),
+        instances_to_update: m.get("InstancesToUpdate").and_then(|p| p.parse().ok()).unwrap_or(0),
+    }).collect()
+}
+
+/// Cancel the instance refresh of `n` in progress.  Instances it already
+/// replaced are left as they are.
+pub fn cancel_instance_refresh(r: &rusoto_core::Region, n: &str) {
+    match autoscaling_call(r, "CancelInstanceRefresh", &[("AutoScalingGroupName", n.to_string())]) {
+        Ok(body) => info!("cancelled instance refresh {} of {}", first_element(&body, "InstanceRefreshId").unwrap_or_default(), n),
+        Err(e) => warn!("WARN: could not cancel the instance refresh of {}: {}", n, e),
+    };
+}
+
+/// Roll back the instance refresh of `n` in progress, replacing the
+/// instances it already updated with ones from the previous launch template
+/// version.
+pub fn rollback_instance_refresh(r: &rusoto_core::Region, n: &str) {
+    match autoscaling_call(r, "RollbackInstanceRefresh", &[("AutoScalingGroupName", n.to_string())]) {
+        Ok(body) => info!("rolling back instance refresh {} of {}", first_element(&body, "InstanceRefreshId").unwrap_or_default(), n),
+        Err(e) => panic!("ERROR: could not roll back the instance refresh of {}: {}", n, e),
+    };
+}
+
+/// The ordered list of AWS calls (and waits) `refresh_instances` makes to
+/// carry out `plan`.  `after_batch` lists what runs after each batch.
+pub fn refresh_calls(plan: &RotationPlan, after_batch: &[String]) -> Vec<String> {
+    let (min_healthy, max_healthy) = healthy_percentages(plan);
+    let mut calls = vec![aws_call("autoscaling", "StartInstanceRefresh", format!("{} (healthy: {}%-{}%, warmup: {}s)",
+        plan.asg, min_healthy, max_healthy, REFRESH_WARMUP))];
+
+    let total = plan.batches.len();
+    for i in 0..total {
+        calls.push(aws_call("autoscaling", "DescribeInstanceRefreshes", format!("{} (until {}% complete)", plan.asg, (i + 1) * 100 / total)));
+        calls.extend(after_batch.iter().map(|c| format!("batch {}: {}", i + 1, c)));
+    };
+    calls
+}
+
+/// Make an Auto Scaling API call that the AWS SDK does not know about yet,
+/// signed the way its own calls are.  The instance refresh calls only
+/// arrived in rusoto 0.45, which, like every release after 0.42, has no
+/// blocking `sync()` calls for the rest of `burnish` to keep using.  Returns the response body, or the
+/// error AWS gave.
+fn autoscaling_call(r: &rusoto_core::Region, action: &str, params: &[(&str, String)]) -> Result<Vec<u8>, String> {
+    let mut request = SignedRequest::new("POST", "autoscaling", r, "/");
+    let mut body = Params::new();
+    body.put("Action", action);
+    body.put("Version", "2011-01-01");
+    for (k, v) in params {
+        body.put(k, v);
+    };
+    request.set_payload(Some(serde_urlencoded::to_string(&body).unwrap()));
+    request.set_content_type("application/x-www-form-urlencoded".to_owned());
+
+    let response = Client::shared().sign_and_dispatch::<_, ()>(request, |response| {
+        Box::new(response.buffer().from_err())
+    }).sync();
+
+    match response {
+        Ok(res) if res.status.is_success() => Ok(res.body.to_vec()),
+        Ok(res) => Err(format!("{}: {}",
+            first_element(&res.body, "Code").unwrap_or_else(|| res.status.to_string()),
+            first_element(&res.body, "Message").unwrap_or_default())),
+        Err(error) => Err(format!("{:?}", error)),
+    }
+}
+
+/// The text of the first `<name>` element in `body`.
+fn first_element(body: &[u8], name: &str) -> Option<String> {
+    let mut current = String::new();
+    for e in EventReader::new(body) {
+        match e {
+            Ok(XmlEvent::StartElement { name: n, .. }) => current = n.local_name,
+            Ok(XmlEvent::Characters(text)) if current == name => return Some(text),
+            Ok(XmlEvent::EndElement { .. }) => current.clear(),
+            _ => {},
+        };
+    };
+    None
+}
+
+/// The members of the `<list>` element in `body`, each as a map of its
+/// child elements' text.
+fn list_members(body: &[u8], list: &str) -> Vec<BTreeMap<String, String>> {
+    let mut members = Vec::new();
+    let mut path: Vec<String> = Vec::new();
+    for e in EventReader::new(body) {
+        match e {
+            Ok(XmlEvent::StartElement { name, .. }) => {
+                if name.local_name == "member" && path.last().map(|p| p == list).unwrap_or(false) {
+                    members.push(BTreeMap::new());
+                };
+                path.push(name.local_name);
+            },
+            Ok(XmlEvent::Characters(text)) if path.len() >= 3 && path[path.len() - 3] == list => {
+                if let Some(m) = members.last_mut() {
+                    m.insert(path[path.len() - 1].clone(), text);
+                };
+            },
+            Ok(XmlEvent::EndElement { .. }) => {
+                path.pop();
+            },
+            _ => {},
+        };
+    };
+    members
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STARTED: &str = r#"<StartInstanceRefreshResponse xmlns="http://autoscaling.amazonaws.com/doc/2011-01-01/">
+  <StartInstanceRefreshResult>
+    <InstanceRefreshId>08b91cf7-8fa6-48af-b6a6-d227f40f1b9b</InstanceRefreshId>
+  </StartInstanceRefreshResult>
+  <ResponseMetadata>
+    <RequestId>7c6e177f-f082-11e1-ac58-3714bEXAMPLE</RequestId>
+  </ResponseMetadata>
+</StartInstanceRefreshResponse>"#;
+
+    const FAILED: &str = r#"<ErrorResponse xmlns="http://autoscaling.amazonaws.com/doc/2011-01-01/">
+  <Error>
+    <Type>Sender</Type>
+    <Code>InstanceRefreshInProgress</Code>
+    <Message>An Instance Refresh is already in progress and blocks the execution of this Instance Refresh.</Message>
+  </Error>
+  <RequestId>7c6e177f-f082-11e1-ac58-3714bEXAMPLE</RequestId>
+</ErrorResponse>"#;
+
+    const DESCRIBED: &str = r#"<DescribeInstanceRefreshesResponse xmlns="http://autoscaling.amazonaws.com/doc/2011-01-01/">
+  <DescribeInstanceRefreshesResult>
+    <InstanceRefreshes>
+      <member>
+        <PercentageComplete>40</PercentageComplete>
+        <InstancesToUpdate>3</InstancesToUpdate>
+        <Status>InProgress</Status>
+        <StatusReason>Waiting for instances to warm up before continuing.</StatusReason>
+        <Preferences>
+          <MinHealthyPercentage>90</MinHealthyPercentage>
+          <InstanceWarmup>300</InstanceWarmup>
+        </Preferences>
+        <InstanceRefreshId>08b91cf7-8fa6-48af-b6a6-d227f40f1b9b</InstanceRefreshId>
+        <AutoScalingGroupName>my-asg</AutoScalingGroupName>
+      </member>
+      <member>
+        <PercentageComplete>100</PercentageComplete>
+        <InstancesToUpdate>0</InstancesToUpdate>
+        <Status>Successful</Status>
+        <InstanceRefreshId>dd7728d0-5bc4-4575-96a3-1b2c52bf8bb1</InstanceRefreshId>
+        <AutoScalingGroupName>my-asg</AutoScalingGroupName>
+      </member>
+    </InstanceRefreshes>
+  </DescribeInstanceRefreshesResult>
+</DescribeInstanceRefreshesResponse>"#;
+
+    fn plan(desired: i64, surge: usize, unavailable: usize) -> RotationPlan {
+        RotationPlan {
+            asg:         "asg".to_string(),
+            elbs:        Vec::new(),
+            desired,
+            max:         desired,
+            surge_max:   desired + surge as i64,
+            surge,
+            unavailable,
+            batches:     Vec::new(),
+        }
+    }
+
+    #[test]
+    fn first_element_finds_the_refresh_id() {
+        assert_eq!(first_element(STARTED.as_bytes(), "InstanceRefreshId"), Some("08b91cf7-8fa6-48af-b6a6-d227f40f1b9b".to_string()));
+    }
+
+    #[test]
+    fn first_element_reads_errors() {
+        assert_eq!(first_element(FAILED.as_bytes(), "Code"), Some("InstanceRefreshInProgress".to_string()));
+        assert!(first_element(FAILED.as_bytes(), "Message").unwrap().starts_with("An Instance Refresh is already in progress"));
+    }
+
+    #[test]
+    fn first_element_is_none_when_missing() {
+        assert_eq!(first_element(STARTED.as_bytes(), "Code"), None);
+        assert_eq!(first_element(b"not xml", "Code"), None);
+    }
+
+    #[test]
+    fn list_members_reads_each_member() {
+        let members = list_members(DESCRIBED.as_bytes(), "InstanceRefreshes");
+        assert_eq!(members.len(), 2);
+        assert_eq!(members[0].get("Status").map(|s| s.as_str()), Some("InProgress"));
+        assert_eq!(members[1].get("InstanceRefreshId").map(|s| s.as_str()), Some("dd7728d0-5bc4-4575-96a3-1b2c52bf8bb1"));
+    }
+
+    #[test]
+    fn list_members_skips_nested_elements() {
+        let members = list_members(DESCRIBED.as_bytes(), "InstanceRefreshes");
+        assert!(!members[0].contains_key("MinHealthyPercentage"));
+        assert!(!members[0].contains_key("InstanceWarmup"));
+    }
+
+    #[test]
+    fn list_members_of_a_missing_list_is_empty() {
+        assert!(list_members(STARTED.as_bytes(), "InstanceRefreshes").is_empty());
+    }
+
+    #[test]
+    fn instance_refreshes_are_parsed() {
+        let refreshes = instance_refreshes(DESCRIBED.as_bytes());
+        assert_eq!(refreshes.len(), 2);
+        assert_eq!(refreshes[0].id, "08b91cf7-8fa6-48af-b6a6-d227f40f1b9b");
+        assert_eq!(refreshes[0].status, "InProgress");
+        assert_eq!(refreshes[0].status_reason, "Waiting for instances to warm up before continuing.");
+        assert_eq!((refreshes[0].percentage_complete, refreshes[0].instances_to_update), (40, 3));
+        assert_eq!((refreshes[1].status.as_str(), refreshes[1].status_reason.as_str()), ("Successful", ""));
+    }
+
+    #[test]
+    fn healthy_percentages_follow_surge_and_unavailable() {
+        assert_eq!(healthy_percentages(&plan(10, 3, 2)), (80, 130));
+        assert_eq!(healthy_percentages(&plan(3, 1, 0)), (100, 134));
+    }
+
+    #[test]
+    fn healthy_percentages_stay_within_100_points() {
+        assert_eq!(healthy_percentages(&plan(4, 8, 0)), (100, 200));
+        assert_eq!(healthy_percentages(&plan(4, 2, 2)), (50, 150));
+    }
+
+    #[test]
+    fn healthy_percentages_of_an_empty_group() {
+        assert_eq!(healthy_percentages(&plan(0, 1, 0)), (100, 100));
+    }
+}
@@ -0,0 +1,319 @@
+use std::panic::{self, AssertUnwindSafe};
+use std::{thread, time};
+use prettytable::{Table, format};
+use yaml_rust::Yaml;
+use alarms::check_alarms;
+use audit::panic_message;
+use deployment::{DeploymentContext, print_plan, resolve_deployment_with, run_deployment, undo_deployment};
+use manifest::{Settings, deployment_settings};
+use strategy::StrategyRegistry;
+use utils::{dry_run, parse_region};
+
+/// How a deployment goes out to the regions of an environment, from the
+/// environment's `regions` and `region_rollout` in the universe.
+#[derive(Debug, Clone, Default)]
+pub struct Rollout {
+    pub regions:  Vec<rusoto_core::Region>,
+    /// Deploy to every region at once instead of one after another.
+    pub parallel: bool,
+    /// Minutes to watch a region's alarms before moving on to the next.
+    pub bake:     u64,
+    /// Roll back the regions already deployed when a region fails.
+    pub rollback: bool,
+}
+
+/// The regions `env` declares, in the order they are deployed.
+pub fn environment_regions(u: &Yaml, env: &str) -> Vec<rusoto_core::Region> {
+    let regions = match u["environments"][env]["regions"] {
+        Yaml::String(ref s) => vec![Yaml::String(s.clone())],
+        ref y => y.as_vec().cloned().unwrap_or_default(),
+    };
+
+    regions.iter().map(|y| {
+        let name = y.as_str().unwrap_or("");
+        match parse_region(name) {
+            Ok(r) => r,
+            Err(_) => panic!("ERROR: environment {} has unknown region {:?}", env, name),
+        }
+    }).collect()
+}
+
+pub fn rollout(u: &Yaml, env: &str) -> Rollout {
+    let r = &u["environments"][env]["region_rollout"];
+    let parallel = match r["mode"].as_str().unwrap_or("sequential") {
+        "sequential" => false,
+        "parallel" => true,
+        mode => panic!("ERROR: environment {} has unknown region_rollout mode {} (available: sequential, parallel)", env, mode),
+    };
+
+    Rollout {
+        regions:  environment_regions(u, env),
+        parallel,
+        bake:     r["bake"].as_i64().unwrap_or(0) as u64,
+        rollback: r["rollback"].as_bool().unwrap_or(true),
+    }
+}
+
+/// The universe as seen from `region`: anything under the application's
+/// `regions.<region>` block replaces the application's own setting, so ELBs,
+/// security groups and the like can differ between regions.  Mappings such
+/// as `elb` are merged one level deep, so a region only needs to list the
+/// environments it changes.
+pub fn regional_universe(u: &Yaml, app: &str, region: &str) -> Yaml {
+    let overrides = match u["applications"][app]["regions"][region].as_hash() {
+        Some(h) => h.clone(),
+        None => return u.clone(),
+    };
+
+    let mut u = u.clone();
+    if let Yaml::Hash(ref mut root) = u {
+        if let Some(&mut Yaml::Hash(ref mut apps)) = root.get_mut(&Yaml::String("applications".to_string())) {
+            if let Some(&mut Yaml::Hash(ref mut a)) = apps.get_mut(&Yaml::String(app.to_string())) {
+                for (k, v) in overrides {
+                    match (a.get_mut(&k), v) {
+                        (Some(&mut Yaml::Hash(ref mut into)), Yaml::Hash(from)) => {
+                            for (ik, iv) in from {
+                                into.insert(ik, iv);
+                            };
+                        },
+                        (_, v) => {
+                            a.insert(k, v);
+                        },
+                    };
+                };
+            };
+        };
+    };
+    u
+}
+
+/// Resolve the deployment in every region of its environment before
+/// anything is changed.  Returns an empty list if the environment declares
+/// no regions, in which case the deployment goes to `--region` as before.
+pub fn resolve_regions(r: rusoto_core::Region, m: &clap::ArgMatches, u: &Yaml, s: &StrategyRegistry, overrides: Settings) -> (Rollout, Vec<(Yaml, DeploymentContext)>) {
+    let (settings, _) = deployment_settings(r, m, u, overrides.clone());
+    let app = settings.get("app").and_then(|v| v.as_str()).unwrap_or("").to_string();
+    let env = settings.get("env").and_then(|v| v.as_str()).unwrap_or("").to_string();
+
+    let rollout = rollout(u, &env);
+    if rollout.regions.len() > 1 {
+        if let Some(ami) = settings.get("ami").and_then(|v| v.as_str()) {
+            panic!("ERROR: AMI {} only exists in one region, but {} deploys to {}; use an AMI selector instead",
+                ami, env, rollout.regions.iter().map(|r| r.name()).collect::<Vec<_>>().join(", "));
+        };
+    };
+
+    let targets = rollout.regions.iter().map(|region| {
+        let ru = regional_universe(u, &app, region.name());
+        let ctx = resolve_deployment_with(region.clone(), m, &ru, s, overrides.clone());
+        (ru, ctx)
+    }).collect();
+
+    (rollout, targets)
+}
+
+/// Print the plan for every region of the deployment, or for `--region`
+/// alone if its environment declares no regions.
+pub fn plan_regions(r: rusoto_core::Region, m: &clap::ArgMatches, u: &Yaml, s: &StrategyRegistry, overrides: Settings) {
+    let (rollout, targets) = resolve_regions(r.clone(), m, u, s, overrides.clone());
+    if targets.is_empty() {
+        let ctx = resolve_deployment_with(r, m, u, s, overrides);
+        print_plan(&ctx, s.get(&ctx.deploy.strategy).unwrap());
+        return
+    };
+    print_region_plan(&rollout, &targets, s);
+}
+
+fn print_region_plan(rollout: &Rollout, targets: &[(Yaml, DeploymentContext)], s: &StrategyRegistry) {
+    for (i, (_, ctx)) in targets.iter().enumerate() {
+        println!("=== Region {} of {}: {}", i + 1, targets.len(), ctx.region.name());
+        println!();
+        print_plan(ctx, s.get(&ctx.deploy.strategy).unwrap());
+        println!();
+    };
+
+    if targets.len() > 1 {
+        if rollout.parallel {
+            println!("Regions are deployed in parallel.");
+        } else if rollout.bake > 0 {
+            println!("Regions are deployed in order, watching each region's alarms for {} minutes before the next.", rollout.bake);
+        } else {
+            println!("Regions are deployed in order.");
+        };
+        if rollout.rollback {
+            println!("If any region fails, the regions already deployed are rolled back.");
+        } else {
+            println!("If any region fails, the remaining regions are skipped; the regions already deployed are left as they are.");
+        };
+    };
+}
+
+/// Deploy to every region of the deployment's environment, or to `--region`
+/// alone if the environment declares no regions.  A failed region stops the
+/// regions after it and, if the rollout says so, rolls back the regions
+/// already deployed, newest first.
+pub fn deploy_regions(r: rusoto_core::Region, m: &clap::ArgMatches, u: &Yaml, s: &StrategyRegistry, overrides: Settings) {
+    let (rollout, targets) = resolve_regions(r.clone(), m, u, s, overrides.clone());
+    if targets.is_empty() {
+        let ctx = resolve_deployment_with(r, m, u, s, overrides);
+        run_deployment(&ctx, u, s.get(&ctx.deploy.strategy).unwrap());
+        return
+    };
+    if dry_run() {
+        print_region_plan(&rollout, &targets, s);
+        return
+    };
+
+    let names: Vec<String> = targets.iter().map(|(_, c)| c.region.name().to_string()).collect();
+    let mut results: Vec<String> = vec!["not started".to_string(); targets.len()];
+    let mut deployed: Vec<usize> = Vec::new();
+    let mut failed = false;
+
+    if rollout.parallel {
+        info!("deploying to {} in parallel", names.join(", "));
+        let outcomes: Vec<(usize, Result<(), String>)> = thread::scope(|scope| {
+            let handles: Vec<_> = targets.iter().enumerate().map(|(i, (ru, ctx))| {
+                let (ru, ctx) = (ru.clone(), ctx.clone());
+                (i, scope.spawn(move || run_deployment(&ctx, &ru, s.get(&ctx.deploy.strategy).unwrap())))
+            }).collect();
+            handles.into_iter().map(|(i, h)| (i, h.join().map_err(|e| panic_message(&*e)))).collect()
+        });
+
+        for (i, outcome) in outcomes {
+            match outcome {
+                Ok(_) => {
+                    results[i] = "deployed".to_string();
+                    deployed.push(i);
+                },
+                Err(e) => {
+                    results[i] = format!("failed and rolled back: {}", e);
+                    failed = true;
+                },
+            };
+        };
+    } else {
+        for (i, (ru, ctx)) in targets.iter().enumerate() {
+            info!("deploying to {} (region {} of {})", names[i], i + 1, targets.len());
+            let strategy = s.get(&ctx.deploy.strategy).unwrap();
+            if let Err(e) = panic::catch_unwind(AssertUnwindSafe(|| run_deployment(ctx, ru, strategy))) {
+                results[i] = format!("failed and rolled back: {}", panic_message(&*e));
+                failed = true;
+                break
+            };
+            results[i] = "deployed".to_string();
+
+            if i + 1 < targets.len() {
+                if let Err(e) = bake(ctx, rollout.bake) {
+                    results[i] = if !rollout.rollback {
+                        format!("deployed, then failed: {}", e)
+                    } else {
+                        match panic::catch_unwind(AssertUnwindSafe(|| undo_deployment(ctx, ru, strategy, &e))) {
+                            Ok(_) => format!("deployed, then rolled back: {}", e),
+                            Err(re) => format!("deployed, then rollback failed: {}: {}", e, panic_message(&*re)),
+                        }
+                    };
+                    failed = true;
+                    break
+                };
+            };
+            deployed.push(i);
+        };
+    };
+
+    if failed && rollout.rollback {
+        for &i in deployed.iter().rev() {
+            let (ref ru, ref ctx) = targets[i];
+            let rolled_back = panic::catch_unwind(AssertUnwindSafe(|| {
+                undo_deployment(ctx, ru, s.get(&ctx.deploy.strategy).unwrap(), "another region failed");
+            }));
+            results[i] = match rolled_back {
+                Ok(_) => "deployed, then rolled back".to_string(),
+                Err(e) => format!("deployed, then rollback failed: {}", panic_message(&*e)),
+            };
+        };
+    };
+
+    let mut table = Table::new();
+    table.set_format(*format::consts::FORMAT_NO_LINESEP_WITH_TITLE);
+    table.set_titles(row!["Region", "Result"]);
+    for (i, name) in names.iter().enumerate() {
+        table.add_row(row![name, results[i]]);
+    };
+    table.printstd();
+
+    if failed {
+        if rollout.rollback {
+            panic!("ERROR: deployment failed; every region deployed was rolled back");
+        };
+        panic!("ERROR: deployment failed; regions already deployed were left as they are");
+    };
+    info!("deployment to {} is complete", names.join(", "));
+}
+
+/// Watch a freshly deployed region's alarms for `minutes` before the next
+/// region starts.
+fn bake(c: &DeploymentContext, minutes: u64) -> Result<(), String> {
+    if minutes == 0 {
+        return Ok(())
+    };
+
+    info!("baking {} in {} for {} minutes", c.deploy.version, c.region.name(), minutes);
+    for _ in 0..minutes {
+        thread::sleep(time::Duration::from_secs(60));
+        check_alarms(c, &format!("while baking in {}", c.region.name()))?;
+    };
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use yaml_rust::YamlLoader;
+
+    const UNIVERSE: &str = "
+applications:
+  web:
+    elb:
+      prod: web-prod
+      staging: web-staging
+    security_groups:
+      prod: [sg-1, sg-2]
+    instance_type: t3.small
+    regions:
+      eu-west-1:
+        elb:
+          prod: web-prod-eu
+        security_groups:
+          prod: [sg-eu]
+        instance_type: t3.medium
+  api:
+    elb:
+      prod: api-prod
+";
+
+    fn universe() -> Yaml {
+        YamlLoader::load_from_str(UNIVERSE).unwrap().remove(0)
+    }
+
+    #[test]
+    fn a_region_override_keeps_environments_it_does_not_mention() {
+        let u = regional_universe(&universe(), "web", "eu-west-1");
+        let web = &u["applications"]["web"];
+        assert_eq!(web["elb"]["prod"].as_str(), Some("web-prod-eu"));
+        assert_eq!(web["elb"]["staging"].as_str(), Some("web-staging"));
+        assert_eq!(web["security_groups"]["prod"], YamlLoader::load_from_str("[sg-eu]").unwrap()[0]);
+        assert_eq!(web["instance_type"].as_str(), Some("t3.medium"));
+    }
+
+    #[test]
+    fn a_region_override_only_touches_its_application() {
+        let u = regional_universe(&universe(), "web", "eu-west-1");
+        assert_eq!(u["applications"]["api"]["elb"]["prod"].as_str(), Some("api-prod"));
+    }
+
+    #[test]
+    fn a_region_without_overrides_leaves_the_universe_alone() {
+        assert_eq!(regional_universe(&universe(), "web", "us-east-1"), universe());
+        assert_eq!(regional_universe(&universe(), "api", "eu-west-1"), universe());
+    }
+}
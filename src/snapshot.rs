@@ -0,0 +1,529 @@
+use std::fs;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use chrono::{Local, SecondsFormat};
+use prettytable::{Table, format};
+use rusoto_autoscaling::{
+    Autoscaling, AutoscalingClient, CreateOrUpdateTagsType, DeleteTagsType, LaunchTemplateSpecification, Tag, UpdateAutoScalingGroupType,
+};
+use serde_json::Value;
+use autoscalegroup::{AutoScaleGroup, asg_record, current_asg, list_asg};
+use processes::{resume_processes, suspend_processes, without_expired_suspension};
+use audit::audited;
+use utils::{aws_call, confirm, dry_run};
+
+/// Where `burnish` keeps the snapshots it takes before changing an ASG, and
+/// where `autoscalegroup snapshot` writes them without `--file`.
+pub const SNAPSHOT_DIR: &str = "burnish-snapshots";
+
+/// The parts of an ASG that `restore_snapshot` puts back: its size, what it
+/// launches instances from, its suspended processes and its tags.  Tags
+/// AWS reserves (`aws:`) cannot be changed, so they are left out, and a
+/// suspension that has run out is left out so a restore never brings it
+/// back.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AsgSnapshot {
+    pub name:                 std::string::String,
+    pub min_size:             i64,
+    pub max_size:             i64,
+    pub desired_capacity:     i64,
+    pub launch_configuration: std::string::String,
+    pub launch_template:      Option<LaunchTemplateSpecification>,
+    pub suspended_processes:  Vec<std::string::String>,
+    pub tags:                 Vec<Tag>,
+}
+
+impl AsgSnapshot {
+    pub fn of(asg: &AutoScaleGroup) -> AsgSnapshot {
+        let asg = &without_expired_suspension(asg);
+        let mut suspended_processes = asg.suspended_processes.clone();
+        suspended_processes.sort();
+        AsgSnapshot {
+            name:                 asg.name.clone(),
+            min_size:             asg.min_size,
+            max_size:             asg.max_size,
+            desired_capacity:     asg.desired_capacity,
+            launch_configuration: asg.lc_name.clone(),
+            launch_template:      asg.launch_template.clone(),
+            suspended_processes,
+            tags:                 snapshot_tags(asg),
+        }
+    }
+
+    /// The value and whether it propagates to instances of the snapshot's
+    /// `key` tag, if it has one.
+    fn tag(&self, key: &str) -> Option<(&str, bool)> {
+        self.tags.iter().find(|t| t.key == key).map(|t| (t.value.as_deref().unwrap_or(""), t.propagate_at_launch.unwrap_or(false)))
+    }
+}
+
+/// The tags of `asg` that a snapshot keeps.
+fn snapshot_tags(asg: &AutoScaleGroup) -> Vec<Tag> {
+    let mut tags: Vec<Tag> = asg.tags.iter().filter(|t| !t.key.starts_with("aws:")).map(|t| Tag {
+        key:                 t.key.clone(),
+        propagate_at_launch: Some(t.propagate_at_launch.unwrap_or(false)),
+        resource_id:         Some(asg.name.clone()),
+        resource_type:       Some("auto-scaling-group".to_string()),
+        value:               Some(t.value.clone().unwrap_or_default()),
+    }).collect();
+    tags.sort_by(|a, b| a.key.cmp(&b.key));
+    tags
+}
+
+pub fn snapshot_to_json(s: &AsgSnapshot) -> Value {
+    json!({
+        "name": s.name,
+        "min_size": s.min_size,
+        "max_size": s.max_size,
+        "desired_capacity": s.desired_capacity,
+        "launch_configuration": s.launch_configuration,
+        "launch_template": s.launch_template.as_ref().map(|t| json!({
+            "id": t.launch_template_id,
+            "name": t.launch_template_name,
+            "version": t.version,
+        })),
+        "suspended_processes": s.suspended_processes,
+        "tags": s.tags.iter().map(|t| json!({
+            "key": t.key,
+            "value": t.value,
+            "propagate_at_launch": t.propagate_at_launch.unwrap_or(false),
+        })).collect::<Vec<Value>>(),
+    })
+}
+
+pub fn snapshot_from_json(v: &Value) -> Result<AsgSnapshot, String> {
+    let name = match v["name"].as_str() {
+        Some(n) if !n.is_empty() => n.to_string(),
+        _ => return Err("a group has no name".to_string()),
+    };
+    let size = |k: &str| v[k].as_i64().ok_or_else(|| format!("{} has no {}", name, k));
+    let s = |v: &Value| v.as_str().map(|s| s.to_string());
+
+    Ok(AsgSnapshot {
+        min_size:             size("min_size")?,
+        max_size:             size("max_size")?,
+        desired_capacity:     size("desired_capacity")?,
+        launch_configuration: s(&v["launch_configuration"]).unwrap_or_default(),
+        launch_template:      if v["launch_template"].is_object() {
+            Some(LaunchTemplateSpecification {
+                launch_template_id:   s(&v["launch_template"]["id"]),
+                launch_template_name: s(&v["launch_template"]["name"]),
+                version:              s(&v["launch_template"]["version"]),
+            })
+        } else {
+            None
+        },
+        suspended_processes:  v["suspended_processes"].as_array().map(|a| a.iter().filter_map(s).collect()).unwrap_or_default(),
+        tags:                 v["tags"].as_array().map(|a| a.iter().filter_map(|t| Some(Tag {
+            key:                 s(&t["key"])?,
+            propagate_at_launch: Some(t["propagate_at_launch"].as_bool().unwrap_or(false)),
+            resource_id:         Some(name.clone()),
+            resource_type:       Some("auto-scaling-group".to_string()),
+            value:               Some(s(&t["value"]).unwrap_or_default()),
+        })).collect()).unwrap_or_default(),
+        name,
+    })
+}
+
+/// Write `snapshots`, taken in region `r`, to `path` as JSON.
+pub fn write_snapshots(r: &rusoto_core::Region, path: &str, snapshots: &[AsgSnapshot]) -> Result<(), String> {
+    if let Some(dir) = Path::new(path).parent() {
+        if !dir.as_os_str().is_empty() {
+            fs::create_dir_all(dir).map_err(|e| format!("could not create {}: {}", dir.display(), e))?;
+        };
+    };
+    let body = json!({
+        "region": r.name(),
+        "taken_at": Local::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+        "groups": snapshots.iter().map(snapshot_to_json).collect::<Vec<Value>>(),
+    });
+    fs::write(path, serde_json::to_string_pretty(&body).unwrap()).map_err(|e| format!("could not write {}: {}", path, e))
+}
+
+/// The region and snapshots in the file at `path`.
+pub fn read_snapshots(path: &str) -> Result<(String, Vec<AsgSnapshot>), String> {
+    let body = fs::read_to_string(path).map_err(|e| format!("could not read {}: {}", path, e))?;
+    let v: Value = serde_json::from_str(&body).map_err(|e| format!("{} is not a snapshot: {}", path, e))?;
+    let groups = match v["groups"].as_array() {
+        Some(g) => g,
+        None => return Err(format!("{} is not a snapshot: it has no groups", path)),
+    };
+    let snapshots = groups.iter().map(snapshot_from_json).collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("{} is not a snapshot: {}", path, e))?;
+    Ok((v["region"].as_str().unwrap_or("").to_string(), snapshots))
+}
+
+/// A new file in `SNAPSHOT_DIR` for a snapshot of `what` in region `r`.
+/// Regions deployed in parallel snapshot groups of the same name in the same
+/// second, so the name ends with a suffix unique to this snapshot.
+fn snapshot_path(r: &rusoto_core::Region, what: &str) -> String {
+    static TAKEN: AtomicUsize = AtomicUsize::new(0);
+    let now = Local::now();
+    format!("{}/{}-{}-{}-{}.{:09}.{}.json", SNAPSHOT_DIR, what, r.name(), now.format("%Y%m%dT%H%M%S"),
+            std::process::id(), now.timestamp_subsec_nanos(), TAKEN.fetch_add(1, Ordering::SeqCst))
+}
+
+/// Snapshot `asg` into `SNAPSHOT_DIR` before `burnish` changes it, so that
+/// the change can be undone by hand with `autoscalegroup restore` if
+/// `burnish` itself cannot undo it.  A snapshot that cannot be written is
+/// only warned about.  Returns the snapshot.
+pub fn keep_snapshot(r: &rusoto_core::Region, asg: &AutoScaleGroup) -> AsgSnapshot {
+    let snapshot = AsgSnapshot::of(asg);
+    if dry_run() {
+        return snapshot
+    };
+
+    let path = snapshot_path(r, &asg.name);
+    match write_snapshots(r, &path, std::slice::from_ref(&snapshot)) {
+        Ok(_) => info!("saved a snapshot of {} to {}", asg.name, path),
+        Err(e) => warn!("WARN: could not save a snapshot of {}: {}", asg.name, e),
+    };
+    snapshot
+}
+
+fn launch_of(lc: &str, lt: &Option<LaunchTemplateSpecification>) -> String {
+    match lt {
+        Some(t) => format!("{} ({})",
+            t.launch_template_name.clone().or_else(|| t.launch_template_id.clone()).unwrap_or_default(),
+            t.version.clone().unwrap_or_else(|| "$Default".to_string())),
+        None => lc.to_string(),
+    }
+}
+
+fn tag_description(t: Option<(&str, bool)>) -> String {
+    match t {
+        Some((v, true)) => format!("{} (propagated)", v),
+        Some((v, false)) => v.to_string(),
+        None => "-".to_string(),
+    }
+}
+
+/// Each setting of `asg` that differs from `s`, as its name, its current
+/// value and its value in the snapshot.
+pub fn snapshot_diff(asg: &AutoScaleGroup, s: &AsgSnapshot) -> Vec<(String, String, String)> {
+    let current = AsgSnapshot::of(asg);
+    let mut diff = Vec::new();
+    let mut compare = |setting: &str, now: String, then: String| {
+        if now != then {
+            diff.push((setting.to_string(), now, then));
+        };
+    };
+
+    compare("Min Size", current.min_size.to_string(), s.min_size.to_string());
+    compare("Max Size", current.max_size.to_string(), s.max_size.to_string());
+    compare("Desired", current.desired_capacity.to_string(), s.desired_capacity.to_string());
+    compare("Launch Config",
+        launch_of(&current.launch_configuration, &current.launch_template),
+        launch_of(&s.launch_configuration, &s.launch_template));
+    compare("Suspended Processes", current.suspended_processes.join(", "), s.suspended_processes.join(", "));
+
+    let mut keys: Vec<&str> = current.tags.iter().chain(s.tags.iter()).map(|t| t.key.as_str()).collect();
+    keys.sort();
+    keys.dedup();
+    for k in keys {
+        compare(&format!("Tag {}", k), tag_description(current.tag(k)), tag_description(s.tag(k)));
+    };
+    diff
+}
+
+/// The changes that would bring `asg` back to `s`: whether its size or
+/// launch source differ, the processes to suspend and resume, and the tags
+/// to set and delete.
+struct Changes {
+    update:  bool,
+    suspend: Vec<String>,
+    resume:  Vec<String>,
+    set:     Vec<Tag>,
+    delete:  Vec<Tag>,
+}
+
+fn changes(asg: &AutoScaleGroup, s: &AsgSnapshot) -> Changes {
+    let current = AsgSnapshot::of(asg);
+    Changes {
+        update:  (current.min_size, current.max_size, current.desired_capacity) != (s.min_size, s.max_size, s.desired_capacity)
+            || launch_of(&current.launch_configuration, &current.launch_template) != launch_of(&s.launch_configuration, &s.launch_template),
+        suspend: s.suspended_processes.iter().filter(|p| !current.suspended_processes.contains(p)).cloned().collect(),
+        resume:  current.suspended_processes.iter().filter(|p| !s.suspended_processes.contains(p)).cloned().collect(),
+        set:     s.tags.iter().filter(|t| current.tag(&t.key) != s.tag(&t.key)).cloned().collect(),
+        delete:  current.tags.iter().filter(|t| s.tag(&t.key).is_none()).cloned().collect(),
+    }
+}
+
+fn tag_keys(tags: &[Tag]) -> String {
+    tags.iter().map(|t| t.key.clone()).collect::<Vec<_>>().join(", ")
+}
+
+/// The ordered list of AWS calls `restore_snapshot` makes to bring `asg`
+/// back to `s`.
+pub fn restore_calls(asg: &AutoScaleGroup, s: &AsgSnapshot) -> Vec<String> {
+    let c = changes(asg, s);
+    let mut calls = Vec::new();
+    if c.update {
+        calls.push(aws_call("autoscaling", "UpdateAutoScalingGroup", format!("{} (min: {}, max: {}, desired: {}, launch: {})",
+            s.name, s.min_size, s.max_size, s.desired_capacity, launch_of(&s.launch_configuration, &s.launch_template))));
+    };
+    if !c.resume.is_empty() {
+        calls.push(aws_call("autoscaling", "ResumeProcesses", format!("{} ({})", s.name, c.resume.join(", "))));
+    };
+    if !c.suspend.is_empty() {
+        calls.push(aws_call("autoscaling", "SuspendProcesses", format!("{} ({})", s.name, c.suspend.join(", "))));
+    };
+    if !c.set.is_empty() {
+        calls.push(aws_call("autoscaling", "CreateOrUpdateTags", format!("{} ({})", s.name, tag_keys(&c.set))));
+    };
+    if !c.delete.is_empty() {
+        calls.push(aws_call("autoscaling", "DeleteTags", format!("{} ({})", s.name, tag_keys(&c.delete))));
+    };
+    calls
+}
+
+/// Put ASG `s.name` back the way it was when `s` was taken.  Only what
+/// differs is changed.  In a dry run, the calls it would make are printed
+/// instead.
+pub fn restore_snapshot(r: rusoto_core::Region, s: &AsgSnapshot) {
+    let asg = current_asg(r.clone(), &s.name);
+    if dry_run() {
+        for call in restore_calls(&asg, s) {
+            println!("[dry-run] {}", call);
+        };
+        return
+    };
+
+    let c = changes(&asg, s);
+    let as_client = AutoscalingClient::new(r.to_owned());
+    if c.update {
+        // AWS wants a launch template by its ID or its name, not both.
+        let launch_template = s.launch_template.clone().map(|t| LaunchTemplateSpecification {
+            launch_template_name: if t.launch_template_id.is_some() { None } else { t.launch_template_name },
+            ..t
+        });
+        let req = UpdateAutoScalingGroupType {
+            auto_scaling_group_name:   s.name.clone(),
+            min_size:                  Some(s.min_size),
+            max_size:                  Some(s.max_size),
+            desired_capacity:          Some(s.desired_capacity),
+            launch_configuration_name: if launch_template.is_none() { Some(s.launch_configuration.clone()) } else { None },
+            launch_template,
+            ..Default::default()
+        };
+        match as_client.update_auto_scaling_group(req).sync() {
+            Ok(_k) => info!("restored {} to min: {}, max: {}, desired: {}, launch: {}",
+                s.name, s.min_size, s.max_size, s.desired_capacity, launch_of(&s.launch_configuration, &s.launch_template)),
+            Err(error) => panic!("ERROR: {:?}", error),
+        };
+    };
+
+    if !c.resume.is_empty() {
+        resume_processes(r.clone(), &s.name, &c.resume);
+    };
+    if !c.suspend.is_empty() {
+        suspend_processes(r.clone(), &s.name, &c.suspend, None);
+    };
+
+    // Changing processes may have changed the expiry tags, so the tags are
+    // compared afresh.
+    let c = match list_asg(r, s.name.clone()).into_iter().next() {
+        Some(a) => changes(&a, s),
+        None => return,
+    };
+    if !c.set.is_empty() {
+        match as_client.create_or_update_tags(CreateOrUpdateTagsType { tags: c.set.clone() }).sync() {
+            Ok(_k) => info!("restored tags {} of {}", tag_keys(&c.set), s.name),
+            Err(error) => panic!("ERROR: {:?}", error),
+        };
+    };
+    if !c.delete.is_empty() {
+        match as_client.delete_tags(DeleteTagsType { tags: c.delete.clone() }).sync() {
+            Ok(_k) => info!("deleted tags {} of {}", tag_keys(&c.delete), s.name),
+            Err(error) => panic!("ERROR: {:?}", error),
+        };
+    };
+}
+
+pub fn snapshot_cmd(r: rusoto_core::Region, m: &clap::ArgMatches) {
+    let (groups, what) = match m.value_of("name") {
+        Some(n) => (vec![current_asg(r.clone(), n)], n.to_string()),
+        None => {
+            let (app, env) = match (m.value_of("app"), m.value_of("env")) {
+                (Some(a), Some(e)) => (a, e),
+                _ => panic!("ERROR: give either --name or both --app and --env"),
+            };
+            let groups: Vec<AutoScaleGroup> = list_asg(r.clone(), String::new()).into_iter()
+                .filter(|a| a.app_name == app && a.env_name == env)
+                .collect();
+            if groups.is_empty() {
+                panic!("ERROR: no autoscaling groups are tagged app {} and env {}", app, env);
+            };
+            (groups, format!("{}-{}", app, env))
+        },
+    };
+
+    let path = m.value_of("file").map(|f| f.to_string()).unwrap_or_else(|| snapshot_path(&r, &what));
+    let snapshots: Vec<AsgSnapshot> = groups.iter().map(AsgSnapshot::of).collect();
+    if let Err(e) = write_snapshots(&r, &path, &snapshots) {
+        panic!("ERROR: {}", e);
+    };
+    println!("Saved a snapshot of {} to {}", snapshots.iter().map(|s| s.name.clone()).collect::<Vec<_>>().join(", "), path);
+}
+
+pub fn restore_cmd(r: rusoto_core::Region, m: &clap::ArgMatches, u: yaml_rust::Yaml) {
+    let path = m.value_of("file").unwrap();
+    let (region, mut snapshots) = match read_snapshots(path) {
+        Ok(s) => s,
+        Err(e) => panic!("ERROR: {}", e),
+    };
+    if !region.is_empty() && region != r.name() {
+        panic!("ERROR: {} was taken in {}, not {}", path, region, r.name());
+    };
+    if let Some(n) = m.value_of("name") {
+        snapshots.retain(|s| s.name == n);
+        if snapshots.is_empty() {
+            panic!("ERROR: {} has no snapshot of {}", path, n);
+        };
+    };
+
+    let mut table = Table::new();
+    table.set_format(*format::consts::FORMAT_NO_LINESEP_WITH_TITLE);
+    table.set_titles(row!["ASG Name", "Setting", "Current", "Snapshot"]);
+    let mut changed = Vec::new();
+    for s in snapshots {
+        let diff = snapshot_diff(&current_asg(r.clone(), &s.name), &s);
+        for (setting, now, then) in &diff {
+            table.add_row(row![s.name, setting, now, then]);
+        };
+        if !diff.is_empty() {
+            changed.push(s);
+        };
+    };
+
+    if changed.is_empty() {
+        println!("Every group already matches {}; nothing to do", path);
+        return
+    };
+    table.printstd();
+
+    if dry_run() {
+        for s in &changed {
+            restore_snapshot(r.clone(), s);
+        };
+        return
+    };
+    if !m.is_present("yes") && !confirm(&format!("Restore {} group(s) from {}?", changed.len(), path)) {
+        println!("Not restored");
+        return
+    };
+
+    for s in changed {
+        audited(r.clone(), &u, asg_record("restore", r.clone(), &s.name), |a| {
+            a.detail = format!("{} {} -> {}/{}/{} from {}", s.name, a.detail, s.min_size, s.max_size, s.desired_capacity, path);
+            restore_snapshot(r.clone(), &s);
+        });
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tag(key: &str, value: &str, propagate: bool) -> Tag {
+        Tag {
+            key:                 key.to_string(),
+            propagate_at_launch: Some(propagate),
+            resource_id:         Some("asg".to_string()),
+            resource_type:       Some("auto-scaling-group".to_string()),
+            value:               Some(value.to_string()),
+        }
+    }
+
+    fn group() -> AutoScaleGroup {
+        AutoScaleGroup {
+            name:                "asg".to_string(),
+            min_size:            1,
+            max_size:            4,
+            desired_capacity:    2,
+            instance_count:      2,
+            lc_name:             "web-prod-1.2.0-20261019090000".to_string(),
+            launch_template:     None,
+            version:             "1.2.0".to_string(),
+            instances:           Vec::new(),
+            elbs:                Vec::new(),
+            suspended_processes: vec!["Launch".to_string(), "AZRebalance".to_string()],
+            availability_zones:  Vec::new(),
+            subnets:             Vec::new(),
+            tags:                vec![tag("app", "web", true), tag("aws:cloudformation:stack-name", "web", false), tag("owner", "ops", false)],
+            app_name:            "web".to_string(),
+            env_name:            "prod".to_string(),
+        }
+    }
+
+    #[test]
+    fn snapshots_leave_out_aws_tags_and_sort_processes() {
+        let s = AsgSnapshot::of(&group());
+        assert_eq!(s.suspended_processes, vec!["AZRebalance", "Launch"]);
+        assert_eq!(s.tags.iter().map(|t| t.key.as_str()).collect::<Vec<_>>(), vec!["app", "owner"]);
+    }
+
+    #[test]
+    fn json_round_trip_keeps_a_launch_configuration() {
+        let s = AsgSnapshot::of(&group());
+        assert_eq!(snapshot_from_json(&snapshot_to_json(&s)), Ok(s));
+    }
+
+    #[test]
+    fn json_round_trip_keeps_a_launch_template() {
+        let mut asg = group();
+        asg.lc_name = String::new();
+        asg.launch_template = Some(LaunchTemplateSpecification {
+            launch_template_id:   Some("lt-0123".to_string()),
+            launch_template_name: Some("web".to_string()),
+            version:              Some("7".to_string()),
+        });
+        let s = AsgSnapshot::of(&asg);
+        assert_eq!(snapshot_from_json(&snapshot_to_json(&s)), Ok(s));
+    }
+
+    #[test]
+    fn json_without_a_name_or_size_is_refused() {
+        let mut v = snapshot_to_json(&AsgSnapshot::of(&group()));
+        v["max_size"] = Value::Null;
+        assert_eq!(snapshot_from_json(&v), Err("asg has no max_size".to_string()));
+        v["name"] = json!("");
+        assert_eq!(snapshot_from_json(&v), Err("a group has no name".to_string()));
+    }
+
+    #[test]
+    fn an_unchanged_group_has_no_diff() {
+        let asg = group();
+        assert!(snapshot_diff(&asg, &AsgSnapshot::of(&asg)).is_empty());
+    }
+
+    #[test]
+    fn diff_lists_each_changed_setting() {
+        let s = AsgSnapshot::of(&group());
+        let mut asg = group();
+        asg.desired_capacity = 3;
+        asg.lc_name = "web-prod-1.3.0-20261019100000".to_string();
+        asg.suspended_processes = Vec::new();
+        asg.tags = vec![tag("app", "web", false), tag("team", "core", false)];
+
+        let diff = snapshot_diff(&asg, &s);
+        let row = |a: &str, b: &str, c: &str| (a.to_string(), b.to_string(), c.to_string());
+        assert_eq!(diff, vec![
+            row("Desired", "3", "2"),
+            row("Launch Config", "web-prod-1.3.0-20261019100000", "web-prod-1.2.0-20261019090000"),
+            row("Suspended Processes", "", "AZRebalance, Launch"),
+            row("Tag app", "web", "web (propagated)"),
+            row("Tag owner", "-", "ops"),
+            row("Tag team", "core", "-"),
+        ]);
+    }
+
+    #[test]
+    fn paths_differ_between_regions_and_snapshots() {
+        let east = snapshot_path(&rusoto_core::Region::UsEast1, "asg");
+        let west = snapshot_path(&rusoto_core::Region::UsWest2, "asg");
+        assert!(east.starts_with("burnish-snapshots/asg-us-east-1-"));
+        assert!(west.starts_with("burnish-snapshots/asg-us-west-2-"));
+        assert_ne!(east, snapshot_path(&rusoto_core::Region::UsEast1, "asg"));
+    }
+}
@@ -0,0 +1,231 @@
+use deployment::{DeploymentContext, launch_canary, judge_canary, remove_canary,
+    reset_canary, rotate_green, restore_green, scale_blue, scale_blue_calls,
+    judge_canary_calls, remove_canary_calls, rotate_green_calls, full_rotation, rolling_rotation,
+    drain_green, drain_green_calls, attach_green, attach_green_calls, green_drained};
+use approval::{approval_calls, await_approval};
+use autoscalegroup::{outdated_instances, rotation_plan};
+
+/// Strategies are shared between the threads that deploy the members of a
+/// bundle, so they must be `Send + Sync`.
+pub trait DeploymentStrategy: Send + Sync {
+    /// Name used to select this strategy with `--strategy`.
+    fn name(&self) -> &str;
+
+    /// The ordered AWS calls (and waits) `execute` will make.  Must not call
+    /// AWS itself; this is what `deployment plan` prints.
+    fn plan(&self, c: &DeploymentContext) -> Vec<String>;
+
+    /// Carry out the deployment.  An `Err` causes `rollback` to be called.
+    fn execute(&self, c: &DeploymentContext) -> Result<(), String>;
+
+    /// Undo whatever `execute` changed.  The default puts the canary ASG back
+    /// to zero instances and returns the green ASG to its previous launch
+    /// configuration.
+    fn rollback(&self, c: &DeploymentContext) {
+        reset_canary(c);
+        restore_green(c);
+    }
+
+    /// The highest desired capacity of the blue and green ASGs while
+    /// `execute` runs.  Only used for display.
+    fn peak_capacity(&self, c: &DeploymentContext) -> (i64, i64) {
+        (1, rolling_peak(c))
+    }
+}
+
+pub struct Rolling;
+
+impl DeploymentStrategy for Rolling {
+    fn name(&self) -> &str { "rolling" }
+
+    fn plan(&self, c: &DeploymentContext) -> Vec<String> {
+        let mut calls = canary_plan(c);
+        calls.extend(rotate_green_calls(c, &rolling_rotation(c)));
+        calls.extend(remove_canary_calls(c));
+        calls
+    }
+
+    fn execute(&self, c: &DeploymentContext) -> Result<(), String> {
+        if !c.deploy.force {
+            launch_canary(c)?;
+            judge_canary(c)?;
+            await_approval(c)?;
+        };
+        rotate_green(c, &rolling_rotation(c))?;
+        remove_canary(c);
+        Ok(())
+    }
+
+    fn peak_capacity(&self, c: &DeploymentContext) -> (i64, i64) {
+        (canary_size(c), rolling_peak(c))
+    }
+}
+
+pub struct Replacement;
+
+impl DeploymentStrategy for Replacement {
+    fn name(&self) -> &str { "replacement" }
+
+    fn plan(&self, c: &DeploymentContext) -> Vec<String> {
+        let mut calls = canary_plan(c);
+        calls.extend(rotate_green_calls(c, &full_rotation(c)));
+        calls.extend(remove_canary_calls(c));
+        calls
+    }
+
+    fn execute(&self, c: &DeploymentContext) -> Result<(), String> {
+        if !c.deploy.force {
+            launch_canary(c)?;
+            judge_canary(c)?;
+            await_approval(c)?;
+        };
+        rotate_green(c, &full_rotation(c))?;
+        remove_canary(c);
+        Ok(())
+    }
+
+    fn peak_capacity(&self, c: &DeploymentContext) -> (i64, i64) {
+        (canary_size(c), c.green_asg_info.desired_capacity + c.green_asg_info.instance_count)
+    }
+}
+
+pub struct CanaryOnly;
+
+impl DeploymentStrategy for CanaryOnly {
+    fn name(&self) -> &str { "canary-only" }
+
+    fn plan(&self, c: &DeploymentContext) -> Vec<String> {
+        let mut calls = scale_blue_calls(c, 1);
+        if !c.deploy.force {
+            calls.extend(judge_canary_calls(c));
+        };
+        calls.extend(remove_canary_calls(c));
+        calls
+    }
+
+    fn execute(&self, c: &DeploymentContext) -> Result<(), String> {
+        launch_canary(c)?;
+        if !c.deploy.force {
+            judge_canary(c)?;
+        };
+        remove_canary(c);
+        Ok(())
+    }
+
+    fn rollback(&self, c: &DeploymentContext) {
+        reset_canary(c);
+    }
+
+    fn peak_capacity(&self, c: &DeploymentContext) -> (i64, i64) {
+        (1, c.green_asg_info.desired_capacity)
+    }
+}
+
+/// Scale the blue ASG up to the green ASG's size behind the same ELB, judge
+/// it, then take green out of the ELB so that blue carries all the traffic.
+/// Green is replaced while out of traffic and put back behind the ELB before
+/// blue is scaled down again.
+pub struct BlueGreen;
+
+impl DeploymentStrategy for BlueGreen {
+    fn name(&self) -> &str { "blue-green" }
+
+    fn plan(&self, c: &DeploymentContext) -> Vec<String> {
+        let mut calls = scale_blue_calls(c, c.green_asg_info.desired_capacity);
+        if !c.deploy.force {
+            calls.extend(judge_canary_calls(c));
+            calls.extend(approval_calls(c));
+        };
+        calls.extend(drain_green_calls(c));
+        calls.extend(rotate_green_calls(c, &full_rotation(c)));
+        calls.extend(attach_green_calls(c));
+        calls.extend(remove_canary_calls(c));
+        calls
+    }
+
+    fn execute(&self, c: &DeploymentContext) -> Result<(), String> {
+        scale_blue(c, c.green_asg_info.desired_capacity)?;
+        if !c.deploy.force {
+            judge_canary(c)?;
+            await_approval(c)?;
+        };
+        drain_green(c)?;
+        rotate_green(c, &full_rotation(c))?;
+        attach_green(c)?;
+        remove_canary(c);
+        Ok(())
+    }
+
+    /// Green goes back behind the ELB before blue is scaled down, so there is
+    /// always something serving traffic.
+    fn rollback(&self, c: &DeploymentContext) {
+        if green_drained(c) {
+            if let Err(e) = attach_green(c) {
+                warn!("WARN: {}", e);
+            };
+        };
+        reset_canary(c);
+        restore_green(c);
+    }
+
+    fn peak_capacity(&self, c: &DeploymentContext) -> (i64, i64) {
+        (c.green_asg_info.desired_capacity, c.green_asg_info.desired_capacity + c.green_asg_info.instance_count)
+    }
+}
+
+fn rolling_peak(c: &DeploymentContext) -> i64 {
+    rotation_plan(&c.green_asg_info, &outdated_instances(&c.green_asg_info, &c.lc_name), &rolling_rotation(c)).peak_desired()
+}
+
+fn canary_size(c: &DeploymentContext) -> i64 {
+    if c.deploy.force { 0 } else { 1 }
+}
+
+fn canary_plan(c: &DeploymentContext) -> Vec<String> {
+    if c.deploy.force {
+        return Vec::new()
+    };
+
+    let mut calls = scale_blue_calls(c, 1);
+    calls.extend(judge_canary_calls(c));
+    calls.extend(approval_calls(c));
+    calls
+}
+
+/// The set of strategies `deployment do --strategy` may select from.
+/// `StrategyRegistry::default()` holds the built-in strategies; library users
+/// can `register` their own before calling `deployment::do_deployment`.
+pub struct StrategyRegistry {
+    strategies: Vec<Box<dyn DeploymentStrategy>>,
+}
+
+impl StrategyRegistry {
+    pub fn new() -> StrategyRegistry {
+        StrategyRegistry { strategies: Vec::new() }
+    }
+
+    /// Add a strategy, replacing any registered strategy with the same name.
+    pub fn register(&mut self, s: Box<dyn DeploymentStrategy>) {
+        self.strategies.retain(|x| x.name() != s.name());
+        self.strategies.push(s);
+    }
+
+    pub fn get(&self, name: &str) -> Option<&dyn DeploymentStrategy> {
+        self.strategies.iter().find(|s| s.name() == name).map(|s| s.as_ref())
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        self.strategies.iter().map(|s| s.name().to_string()).collect()
+    }
+}
+
+impl Default for StrategyRegistry {
+    fn default() -> StrategyRegistry {
+        let mut registry = StrategyRegistry::new();
+        registry.register(Box::new(Rolling));
+        registry.register(Box::new(Replacement));
+        registry.register(Box::new(CanaryOnly));
+        registry.register(Box::new(BlueGreen));
+        registry
+    }
+}
@@ -4,6 +4,12 @@ use futures::{Future, Stream};
 use rusoto_s3::{S3, S3Client, GetObjectRequest};
 
 pub fn get_universe (u: std::string::String, r: rusoto_core::Region) -> yaml_rust::Yaml {
+    load_yaml(u, r, "universe file")
+}
+
+/// Load the first document of a YAML file from S3 (`s3://bucket/key`) or the
+/// local filesystem.  `what` names the file in error messages.
+pub fn load_yaml (u: std::string::String, r: rusoto_core::Region, what: &str) -> yaml_rust::Yaml {
     if u.starts_with("s3://") {
         let s3_client = S3Client::new(r.to_owned());
         let uri = u.parse::<Uri>().unwrap();
@@ -12,16 +18,14 @@ pub fn get_universe (u: std::string::String, r: rusoto_core::Region) -> yaml_rus
             key: uri.path().to_string(),
             ..Default::default()
         };
-        let result = s3_client.get_object(get_req).sync().expect("Couldn't GET universe file from S3");
+        let result = s3_client.get_object(get_req).sync().unwrap_or_else(|_| panic!("Couldn't GET {} from S3", what));
         let stream = result.body.unwrap();
         let body: Vec<u8> = stream.concat2().wait().unwrap().to_vec();
-        let universes = yaml::YamlLoader::load_from_str(&String::from_utf8_lossy(&body)).unwrap();
-        let universe = universes[0].clone();
-        return universe;
+        let docs = yaml::YamlLoader::load_from_str(&String::from_utf8_lossy(&body)).unwrap();
+        docs[0].clone()
     } else {
-        let f = std::fs::read_to_string(u).expect("failed to open local universe file");
-        let universes = yaml::YamlLoader::load_from_str(&f).unwrap();
-        let universe = universes[0].clone();
-        return universe;
+        let f = std::fs::read_to_string(u).unwrap_or_else(|_| panic!("failed to open local {}", what));
+        let docs = yaml::YamlLoader::load_from_str(&f).unwrap();
+        docs[0].clone()
     }
-}
\ No newline at end of file
+}
@@ -1,6 +1,9 @@
 extern crate rusoto_core;
 
+use std::io::{self, IsTerminal, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
 use rusoto_core::Region;
+use rusoto_ec2::{Ec2, Ec2Client, DescribeInstancesRequest, Instance};
 
 pub fn parse_region(s: &str) -> Result<Region, Region> {
     let v : &str = &s.to_lowercase();
@@ -26,3 +29,86 @@ pub fn parse_region(s: &str) -> Result<Region, Region> {
         _s => Err(Region::UsEast1),
     }
 }
+
+static DRY_RUN: AtomicBool = AtomicBool::new(false);
+
+pub fn set_dry_run(b: bool) {
+    DRY_RUN.store(b, Ordering::SeqCst);
+}
+
+pub fn dry_run() -> bool {
+    DRY_RUN.load(Ordering::SeqCst)
+}
+
+/// Format an AWS API call the way dry runs and deployment plans print it.
+pub fn aws_call(service: &str, operation: &str, detail: String) -> String {
+    format!("{}:{} {}", service, operation, detail)
+}
+
+/// Every item from a paginated AWS call.  `page` makes one request with the
+/// token from the last response, `None` the first time, and returns that
+/// page's items and the token for the next page, if there is one.
+pub fn paginate<T, F>(mut page: F) -> Vec<T>
+    where F: FnMut(Option<String>) -> (Vec<T>, Option<String>)
+{
+    let mut items = Vec::new();
+    let mut token = None;
+    loop {
+        let (mut more, next) = page(token);
+        items.append(&mut more);
+        match next {
+            Some(t) if !t.is_empty() => token = Some(t),
+            _ => return items,
+        };
+    };
+}
+
+/// The EC2 instances with the given IDs, across every page of results.
+pub fn describe_instances(r: Region, ids: &[String]) -> Vec<Instance> {
+    if ids.is_empty() {
+        return Vec::new()
+    };
+
+    let ec2_client = Ec2Client::new(r);
+    let reservations = paginate(|token| {
+        let req = DescribeInstancesRequest {
+            instance_ids: Some(ids.to_vec()),
+            next_token:   token,
+            ..Default::default()
+        };
+        match ec2_client.describe_instances(req).sync() {
+            Ok(res) => (res.reservations.unwrap_or_default(), res.next_token),
+            Err(error) => panic!("ERROR: {:?}", error),
+        }
+    });
+
+    reservations.into_iter().flat_map(|res| res.instances.unwrap_or_default()).collect()
+}
+
+/// Called by every function that changes AWS state before it makes the
+/// change.  In a dry run the call is printed instead and `true` is returned
+/// so the caller can skip it.
+pub fn skip_for_dry_run(service: &str, operation: &str, detail: String) -> bool {
+    if !dry_run() {
+        return false
+    };
+
+    println!("[dry-run] {}", aws_call(service, operation, detail));
+    true
+}
+
+/// Ask a yes/no question on the terminal.  Returns `false` without asking
+/// when stdin is not a terminal.
+pub fn confirm(prompt: &str) -> bool {
+    if !io::stdin().is_terminal() {
+        return false
+    };
+
+    print!("{} [y/N] ", prompt);
+    let _ = io::stdout().flush();
+    let mut answer = String::new();
+    if io::stdin().read_line(&mut answer).is_err() {
+        return false
+    };
+    matches!(answer.trim().to_lowercase().as_str(), "y" | "yes")
+}
@@ -0,0 +1,223 @@
+use std::cmp::Ordering;
+use deployment::Deployment;
+
+/// Ways versions can be compared.  `rN` is an optional `r` followed by an
+/// integer (r41 < r42); `semver` is MAJOR.MINOR.PATCH with an optional `v`
+/// and pre-release; `opaque` versions can only be told apart, not ordered.
+pub const SCHEMES: &[&str] = &["rN", "semver", "opaque"];
+
+pub const DEFAULT_SCHEME: &str = "rN";
+
+/// Check that `v` is a valid version under `scheme`.
+pub fn parse_version(scheme: &str, v: &str) -> Result<(), String> {
+    match scheme {
+        "rN" => parse_rn(v).map(|_| ()),
+        "semver" => parse_semver(v).map(|_| ()),
+        _ => Ok(()),
+    }
+}
+
+/// Order two versions under `scheme`.  `None` means they differ but cannot
+/// be ordered.
+pub fn compare_versions(scheme: &str, a: &str, b: &str) -> Result<Option<Ordering>, String> {
+    if a == b {
+        return Ok(Some(Ordering::Equal))
+    };
+
+    match scheme {
+        "rN" => Ok(Some(parse_rn(a)?.cmp(&parse_rn(b)?))),
+        "semver" => {
+            let (a, b) = (parse_semver(a)?, parse_semver(b)?);
+            Ok(Some(a.0.cmp(&b.0).then_with(|| match (a.1.is_empty(), b.1.is_empty()) {
+                (true, true) => Ordering::Equal,
+                (true, false) => Ordering::Greater,
+                (false, true) => Ordering::Less,
+                (false, false) => compare_pre_releases(&a.1, &b.1),
+            })))
+        },
+        _ => Ok(None),
+    }
+}
+
+fn parse_rn(v: &str) -> Result<u64, String> {
+    v.trim_start_matches(['r', 'R']).parse::<u64>()
+        .map_err(|_| format!("version {} is not of the form rN", v))
+}
+
+/// The MAJOR.MINOR.PATCH numbers and pre-release identifiers of `v`.  Build
+/// metadata after `+` is ignored, as SemVer says it must be.
+fn parse_semver(v: &str) -> Result<(Vec<u64>, Vec<String>), String> {
+    let invalid = || format!("version {} is not a semantic version (MAJOR.MINOR.PATCH)", v);
+    let v = v.strip_prefix('v').unwrap_or(v).split('+').next().unwrap_or("");
+    let (core, pre) = match v.find('-') {
+        Some(i) => (&v[..i], v[i + 1..].split('.').map(|p| p.to_string()).collect()),
+        None => (v, Vec::new()),
+    };
+
+    let parts: Vec<u64> = core.split('.').map(|p| p.parse::<u64>()).collect::<Result<_, _>>().map_err(|_| invalid())?;
+    if parts.len() != 3 || pre.iter().any(|p: &String| p.is_empty()) {
+        return Err(invalid())
+    };
+    Ok((parts, pre))
+}
+
+/// Order two pre-releases identifier by identifier, as SemVer 2.0.0 §11
+/// does: numeric identifiers compare as numbers and rank below alphanumeric
+/// ones, alphanumeric ones compare as ASCII, and when every shared
+/// identifier is equal the shorter pre-release ranks lower (rc < rc.1).
+fn compare_pre_releases(a: &[String], b: &[String]) -> Ordering {
+    let numeric = |p: &str| if p.bytes().all(|c| c.is_ascii_digit()) { p.parse::<u64>().ok() } else { None };
+    a.iter().zip(b.iter()).map(|(a, b)| match (numeric(a), numeric(b)) {
+        (Some(a), Some(b)) => a.cmp(&b),
+        (Some(_), None) => Ordering::Less,
+        (None, Some(_)) => Ordering::Greater,
+        (None, None) => a.cmp(b),
+    }).find(|o| *o != Ordering::Equal).unwrap_or_else(|| a.len().cmp(&b.len()))
+}
+
+/// Describe the move from `current` to the version in `d`, or explain why it
+/// is refused: deploying an older version needs `allow_downgrade` and the
+/// same version needs `redeploy`.
+pub fn check_version(d: &Deployment, current: &str) -> Result<String, String> {
+    if current.is_empty() {
+        return Ok(format!("(none) -> {} (first deployment)", d.version))
+    };
+
+    let movement = match compare_versions(&d.version_scheme, current, &d.version) {
+        Ok(Some(Ordering::Less)) => "upgrade",
+        Ok(Some(Ordering::Equal)) if d.redeploy => "redeploy",
+        Ok(Some(Ordering::Equal)) => {
+            return Err(format!("{} is already running {}; pass --redeploy to deploy it again", d.environment, current))
+        },
+        Ok(Some(Ordering::Greater)) if d.allow_downgrade => "downgrade",
+        Ok(Some(Ordering::Greater)) => {
+            return Err(format!("{} -> {} is a downgrade; pass --allow-downgrade to deploy it", current, d.version))
+        },
+        Ok(None) => "change",
+        Err(_) if d.allow_downgrade => "unordered change",
+        Err(e) => {
+            return Err(format!("cannot compare the running version {} with {}: {}; pass --allow-downgrade to deploy anyway", current, d.version, e))
+        },
+    };
+
+    Ok(format!("{} -> {} ({})", current, d.version, movement))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hooks::Hooks;
+    use autoscalegroup::Amount;
+
+    fn deployment(version: &str, scheme: &str) -> Deployment {
+        Deployment {
+            application:         "web".to_string(),
+            environment:         "prod".to_string(),
+            iam_profile:         String::new(),
+            instance_type:       String::new(),
+            version:             version.to_string(),
+            ami:                 String::new(),
+            force:               false,
+            max_latency:         0.0,
+            max_error_rate:      0.0,
+            user_data:           String::new(),
+            healthcheck_timeout: 0,
+            strategy:            String::new(),
+            max_surge:           Amount::Count(1),
+            max_unavailable:     Amount::Count(0),
+            min_az_capacity:     0.0,
+            rotation_method:     String::new(),
+            hooks:               Hooks::new(),
+            version_scheme:      scheme.to_string(),
+            allow_downgrade:     false,
+            redeploy:            false,
+            approval:            None,
+            approval_timeout:    0,
+        }
+    }
+
+    fn semver(a: &str, b: &str) -> Option<Ordering> {
+        compare_versions("semver", a, b).unwrap()
+    }
+
+    #[test]
+    fn rn_takes_an_optional_r() {
+        assert_eq!(parse_rn("r42"), Ok(42));
+        assert_eq!(parse_rn("R42"), Ok(42));
+        assert_eq!(parse_rn("42"), Ok(42));
+        assert!(parse_rn("r4.2").is_err());
+        assert!(parse_rn("release").is_err());
+    }
+
+    #[test]
+    fn semver_takes_an_optional_v() {
+        assert_eq!(parse_semver("1.2.3"), Ok((vec![1, 2, 3], Vec::new())));
+        assert_eq!(parse_semver("v1.2.3"), Ok((vec![1, 2, 3], Vec::new())));
+        assert_eq!(parse_semver("v1.2.3-rc.1+build.7"), Ok((vec![1, 2, 3], vec!["rc".to_string(), "1".to_string()])));
+    }
+
+    #[test]
+    fn malformed_semver_is_refused() {
+        for v in &["1.2", "1.2.3.4", "1.x.3", "vv1.2.3", "1.2.3-", "1.2.3-rc..1", ""] {
+            assert!(parse_semver(v).is_err(), "{} parsed", v);
+        };
+    }
+
+    #[test]
+    fn numeric_pre_release_identifiers_compare_as_numbers() {
+        assert_eq!(semver("1.0.0-rc.9", "1.0.0-rc.10"), Some(Ordering::Less));
+        assert_eq!(semver("1.0.0-rc.10", "1.0.0-rc.9"), Some(Ordering::Greater));
+    }
+
+    #[test]
+    fn pre_releases_follow_semver_precedence() {
+        let ordered = ["1.0.0-alpha", "1.0.0-alpha.1", "1.0.0-alpha.beta", "1.0.0-beta", "1.0.0-beta.2",
+                       "1.0.0-beta.11", "1.0.0-rc.1", "1.0.0"];
+        for w in ordered.windows(2) {
+            assert_eq!(semver(w[0], w[1]), Some(Ordering::Less), "{} < {}", w[0], w[1]);
+        };
+    }
+
+    #[test]
+    fn a_release_ranks_above_its_pre_releases() {
+        assert_eq!(semver("1.0.0", "1.0.0-rc.1"), Some(Ordering::Greater));
+        assert_eq!(semver("1.0.0-rc.1", "0.9.9"), Some(Ordering::Greater));
+        assert_eq!(semver("v1.0.0", "1.0.0+build.2"), Some(Ordering::Equal));
+    }
+
+    #[test]
+    fn opaque_versions_are_only_told_apart() {
+        assert_eq!(compare_versions("opaque", "abc", "abc"), Ok(Some(Ordering::Equal)));
+        assert_eq!(compare_versions("opaque", "abc", "def"), Ok(None));
+    }
+
+    #[test]
+    fn check_version_allows_upgrades_and_first_deployments() {
+        assert_eq!(check_version(&deployment("r42", "rN"), ""), Ok("(none) -> r42 (first deployment)".to_string()));
+        assert_eq!(check_version(&deployment("r42", "rN"), "r41"), Ok("r41 -> r42 (upgrade)".to_string()));
+        assert_eq!(check_version(&deployment("1.0.0-rc.10", "semver"), "1.0.0-rc.9"),
+                   Ok("1.0.0-rc.9 -> 1.0.0-rc.10 (upgrade)".to_string()));
+        assert_eq!(check_version(&deployment("def", "opaque"), "abc"), Ok("abc -> def (change)".to_string()));
+    }
+
+    #[test]
+    fn check_version_refuses_downgrades_and_redeploys_unless_allowed() {
+        let mut d = deployment("r41", "rN");
+        assert!(check_version(&d, "r42").unwrap_err().contains("--allow-downgrade"));
+        d.allow_downgrade = true;
+        assert_eq!(check_version(&d, "r42"), Ok("r42 -> r41 (downgrade)".to_string()));
+
+        let mut d = deployment("r42", "rN");
+        assert!(check_version(&d, "r42").unwrap_err().contains("--redeploy"));
+        d.redeploy = true;
+        assert_eq!(check_version(&d, "r42"), Ok("r42 -> r42 (redeploy)".to_string()));
+    }
+
+    #[test]
+    fn check_version_refuses_versions_it_cannot_compare_unless_allowed() {
+        let mut d = deployment("r42", "rN");
+        assert!(check_version(&d, "latest").unwrap_err().starts_with("cannot compare the running version latest"));
+        d.allow_downgrade = true;
+        assert_eq!(check_version(&d, "latest"), Ok("latest -> r42 (unordered change)".to_string()));
+    }
+}